@@ -0,0 +1,202 @@
+use arrow_array::{ArrayRef, RecordBatch};
+use arrow_array::builder::{Float32Builder, Float64Builder, StringBuilder,
+                           StringDictionaryBuilder, UInt32Builder};
+use arrow_array::types::Int32Type;
+use arrow_schema::{DataType as ArrowType, Field, Schema};
+use arrow_ipc::writer::FileWriter;
+use failure::Error;
+use parquet::arrow::ArrowWriter;
+use parquet::basic::Compression;
+use parquet::file::properties::WriterProperties;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use mifc::{MIFC, Value};
+use sd3::RowContext;
+use output::{Destination, Writer};
+use workbook::Sheet;
+
+/// How a column is stored in a columnar output
+#[derive(Debug, Copy, Clone)]
+enum Kind {
+    Text,
+    Float64,
+    Float32,
+    /// Text with only a few distinct values, stored as a dictionary
+    Category,
+}
+
+use self::Kind::*;
+
+/// The kind and nullability of each MIFC column, in the same order as `MIFC::HEADERS`
+const MIFC_KINDS: [(Kind, bool); 17] = [
+    (Text, false), (Text, true), (Text, true), (Text, false), (Text, false), (Text, true),
+    (Text, false), (Float64, false), (Float64, false), (Float64, false), (Float64, true),
+    (Category, true), (Text, true), (Text, true), (Text, true), (Float32, true), (Text, true),
+];
+
+/// Columnar file format to write
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Container {
+    Parquet,
+    ArrowIpc,
+}
+
+/// Collects every normalized row of a workbook (or of a merged run) into columns, then writes them as a single
+/// Parquet or Arrow IPC file with a typed schema
+pub struct ColumnarWriter {
+    dest: Destination,
+    container: Container,
+    columns: Vec<Column>,
+    workbook: String,
+    file: StringDictionaryBuilder<Int32Type>,
+    sheet: StringDictionaryBuilder<Int32Type>,
+    row: UInt32Builder,
+}
+
+enum Column {
+    Text(StringBuilder),
+    Float64(Float64Builder),
+    Float32(Float32Builder),
+    Category(StringDictionaryBuilder<Int32Type>),
+}
+
+impl ColumnarWriter {
+    pub fn new(dest: Destination, container: Container) -> Self {
+        let columns = MIFC_KINDS.iter()
+            .map(|&(kind, _)| match kind {
+                Text => Column::Text(StringBuilder::new()),
+                Float64 => Column::Float64(Float64Builder::new()),
+                Float32 => Column::Float32(Float32Builder::new()),
+                Category => Column::Category(StringDictionaryBuilder::new()),
+            })
+            .collect();
+
+        ColumnarWriter {
+            dest,
+            container,
+            columns,
+            workbook: String::new(),
+            file: StringDictionaryBuilder::new(),
+            sheet: StringDictionaryBuilder::new(),
+            row: UInt32Builder::new(),
+        }
+    }
+
+    fn schema() -> Schema {
+        let field = |name: &str, kind: Kind, nullable: bool| {
+            let data_type = match kind {
+                Text => ArrowType::Utf8,
+                Float64 => ArrowType::Float64,
+                Float32 => ArrowType::Float32,
+                Category => ArrowType::Dictionary(Box::new(ArrowType::Int32), Box::new(ArrowType::Utf8)),
+            };
+            Field::new(name, data_type, nullable)
+        };
+
+        let fields: Vec<_> = MIFC::HEADERS.iter()
+            .zip(MIFC_KINDS.iter())
+            .map(|(name, &(kind, nullable))| field(name, kind, nullable))
+            .chain(vec![
+                field("Source File", Category, false),
+                field("Source Sheet", Category, false),
+                Field::new("Source Row", ArrowType::UInt32, false),
+            ])
+            .collect();
+        Schema::new(fields)
+    }
+}
+
+impl Writer for ColumnarWriter {
+    fn start_workbook(&mut self, wb_path: &Path) -> Result<(), Error> {
+        self.workbook = wb_path.to_string_lossy().into_owned();
+        Ok(())
+    }
+
+    fn start_sheet(&mut self, _sheet: &Sheet) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn write(&mut self, ctx: &RowContext, row: &MIFC) -> Result<(), Error> {
+        for (column, value) in self.columns.iter_mut().zip(row.values().iter()) {
+            match (column, *value) {
+                (Column::Text(b), Value::Text(s)) => b.append_value(s),
+                (Column::Text(b), _) => b.append_null(),
+                (Column::Category(b), Value::Text(s)) => { b.append(s)?; },
+                (Column::Category(b), _) => b.append_null(),
+                (Column::Float64(b), Value::Number(n)) => b.append_value(n),
+                (Column::Float64(b), _) => b.append_null(),
+                (Column::Float32(b), Value::Number(n)) => b.append_value(n as f32),
+                (Column::Float32(b), _) => b.append_null(),
+            }
+        }
+        self.file.append(&self.workbook)?;
+        self.sheet.append(&ctx.sheet)?;
+        self.row.append_value(ctx.row);
+        Ok(())
+    }
+
+    fn finish(mut self: Box<Self>) -> Result<Vec<PathBuf>, Error> {
+        let schema = Arc::new(Self::schema());
+        let mut arrays: Vec<ArrayRef> = self.columns.iter_mut()
+            .map(|c| match c {
+                Column::Text(b) => Arc::new(b.finish()) as ArrayRef,
+                Column::Float64(b) => Arc::new(b.finish()),
+                Column::Float32(b) => Arc::new(b.finish()),
+                Column::Category(b) => Arc::new(b.finish()),
+            })
+            .collect();
+        arrays.push(Arc::new(self.file.finish()));
+        arrays.push(Arc::new(self.sheet.finish()));
+        arrays.push(Arc::new(self.row.finish()));
+        let batch = RecordBatch::try_new(schema.clone(), arrays)?;
+
+        let file = self.dest.create()?;
+        let file = match self.container {
+            Container::Parquet => {
+                let props = WriterProperties::builder()
+                    .set_compression(Compression::SNAPPY)
+                    .build();
+                let mut writer = ArrowWriter::try_new(file, schema, Some(props))?;
+                writer.write(&batch)?;
+                writer.into_inner()?
+            },
+            Container::ArrowIpc => {
+                let mut writer = FileWriter::try_new(file, &schema)?;
+                writer.write(&batch)?;
+                writer.into_inner()?
+            },
+        };
+        file.finish()?;
+        Ok(vec![self.dest.path()])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+    use std::fs::File;
+    use utils::{TempDir, mifc_row};
+
+    #[test]
+    fn parquet_schema_round_trip() {
+        let dir = TempDir::new("parquet");
+        let path = dir.join("study.parquet");
+        let mut writer = Box::new(ColumnarWriter::new(Destination::File(path.clone()), Container::Parquet));
+        writer.start_workbook(Path::new("study.xlsx")).unwrap();
+        let ctx = RowContext { sheet: "Sheet1".to_string(), row: 2 };
+        writer.write(&ctx, &mifc_row("A1", Some(12.5))).unwrap();
+        writer.write(&RowContext { row: 3, ..ctx }, &mifc_row("A2", None)).unwrap();
+        writer.finish().unwrap();
+
+        let reader = ParquetRecordBatchReaderBuilder::try_new(File::open(&path).unwrap()).unwrap();
+        let schema = reader.schema().clone();
+        let batches: Vec<_> = reader.build().unwrap().collect::<Result<_, _>>().unwrap();
+
+        assert_eq!(batches.iter().map(RecordBatch::num_rows).sum::<usize>(), 2);
+        assert_eq!(schema.field_with_name("Day").unwrap().data_type(), &ArrowType::Float64);
+        assert_eq!(schema.field_with_name("Chip ID").unwrap().data_type(), &ArrowType::Utf8);
+        assert!(matches!(schema.field_with_name("Value Unit").unwrap().data_type(), ArrowType::Dictionary(..)));
+        assert_eq!(batches[0].column_by_name("Value").unwrap().null_count(), 1);
+    }
+}
@@ -0,0 +1,90 @@
+use failure::{Error, ResultExt};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// The config file that is read from the working directory, unless another is given with "--config"
+pub const FILE_NAME: &str = "sd3norm.toml";
+
+/// Defaults for the command line options, read from a TOML file. Each key is an option's long name,
+/// and an option given on the command line takes precedence over its key
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config {
+    pub input: Option<Vec<PathBuf>>,
+    pub input_format: Option<String>,
+    pub extensions: Option<Vec<String>>,
+    pub append: Option<String>,
+    pub name_template: Option<String>,
+    pub verbose: Option<usize>,
+    pub out_dir: Option<PathBuf>,
+    pub flatten: Option<bool>,
+    pub format: Option<String>,
+    pub on_invalid: Option<String>,
+    pub sqlite: Option<PathBuf>,
+    pub merge: Option<PathBuf>,
+    pub stdout: Option<bool>,
+    pub force: Option<bool>,
+    pub no_clobber: Option<bool>,
+    pub on_collision: Option<String>,
+    pub strict: Option<bool>,
+    pub incremental: Option<bool>,
+    pub provenance: Option<bool>,
+    pub fail_fast: Option<bool>,
+    pub jobs: Option<usize>,
+    pub json_summary: Option<bool>,
+}
+
+impl Config {
+    /// Read the config file at `path`, or in the working directory if no `path` is given.
+    /// Returns where it was read from, or `None` if there is no config file in the working directory
+    pub fn find(path: Option<&Path>) -> Result<Option<(PathBuf, Config)>, Error> {
+        let path = match path {
+            Some(p) => p.to_path_buf(),
+            None if Path::new(FILE_NAME).is_file() => PathBuf::from(FILE_NAME),
+            None => return Ok(None),
+        };
+        let text = fs::read_to_string(&path).context(format!("reading config <{}>", path.display()))?;
+        let mut config: Config = ::toml::from_str(&text).context(format!("reading config <{}>", path.display()))?;
+        config.resolve_paths(path.parent().unwrap_or_else(|| Path::new("")));
+        Ok(Some((path, config)))
+    }
+
+    /// Make the paths in a config file relative to the directory that it is in, rather than the working directory
+    fn resolve_paths(&mut self, dir: &Path) {
+        for p in self.input.iter_mut().flatten()
+            .chain(self.out_dir.as_mut())
+            .chain(self.sqlite.as_mut())
+            .chain(self.merge.as_mut())
+        {
+            *p = dir.join(&*p);
+        }
+    }
+
+    pub fn to_toml(&self) -> Result<String, Error> {
+        Ok(::toml::to_string(self)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use utils::TempDir;
+
+    #[test]
+    fn paths_are_relative_to_the_config() {
+        let dir = TempDir::new("config");
+        let path = dir.join(FILE_NAME);
+        fs::write(&path, "input = [\"data\"]\nout-dir = \"/out\"\nformat = \"xlsx\"\nfail-fast = true\n").unwrap();
+        let (found, config) = Config::find(Some(&path)).unwrap().unwrap();
+        fs::write(&path, "formats = \"xlsx\"\n").unwrap();
+        let misspelled = Config::find(Some(&path));
+
+        assert_eq!(found, path);
+        assert_eq!(config.input, Some(vec![dir.join("data")]));
+        assert_eq!(config.out_dir, Some(PathBuf::from("/out")));
+        assert_eq!(config.format.as_deref(), Some("xlsx"));
+        assert_eq!(config.fail_fast, Some(true));
+        assert_eq!(config.strict, None);
+        assert!(misspelled.is_err());
+    }
+}
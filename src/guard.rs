@@ -0,0 +1,181 @@
+use failure::Error;
+use std::collections::HashSet;
+use std::env;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// What to do when an output file already exists before it is written
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Existing {
+    /// Fail, instead of replacing the file
+    Refuse,
+    /// Replace the file
+    Overwrite,
+    /// Don't normalize the input that would be written to the file
+    Skip,
+}
+
+/// What to do when more than one output of a run would be written to the same file
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Collision {
+    Error,
+    /// Add a number to the end of the later outputs' names
+    Suffix,
+}
+
+impl FromStr for Collision {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "error" => Ok(Collision::Error),
+            "suffix" => Ok(Collision::Suffix),
+            _ => Err(format_err!("unknown collision handling <{}>", s)),
+        }
+    }
+}
+
+impl fmt::Display for Collision {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            Collision::Error => "error",
+            Collision::Suffix => "suffix",
+        })
+    }
+}
+
+/// Keeps track of the files written during a run, so that outputs never overwrite an input,
+/// each other, or (unless asked to) the files of an earlier run
+pub struct OutputGuard {
+    existing: Existing,
+    collision: Collision,
+    inputs: HashSet<PathBuf>,
+    claimed: HashSet<PathBuf>,
+    /// Files written by an earlier run, which may be replaced whatever `existing` says
+    adopted: HashSet<PathBuf>,
+}
+
+impl OutputGuard {
+    pub fn new(existing: Existing, collision: Collision, inputs: &[PathBuf]) -> Self {
+        OutputGuard {
+            existing,
+            collision,
+            inputs: inputs.iter().map(|i| absolute(i)).collect(),
+            claimed: HashSet::new(),
+            adopted: HashSet::new(),
+        }
+    }
+
+    /// Let files that an earlier run wrote, like those recorded in an `--incremental` manifest, be replaced
+    pub fn adopt(&mut self, outputs: &[PathBuf]) {
+        self.adopted.extend(outputs.iter().map(|o| absolute(o)));
+    }
+
+    /// Claim the output files of one input, returning the paths to write them to (which are suffixed
+    /// if they collided with an earlier output), or `None` if the input should be skipped
+    pub fn claim(&mut self, paths: Vec<PathBuf>) -> Result<Option<Vec<PathBuf>>, Error> {
+        let mut resolved = Vec::with_capacity(paths.len());
+        let mut keys = Vec::with_capacity(paths.len());
+
+        for path in paths {
+            let mut candidate = path.clone();
+            let mut n = 1;
+            while self.is_claimed(&candidate, &keys) {
+                if self.collision == Collision::Error {
+                    bail!("more than one output would be written to <{}>, use a \"--name-template\" or \
+                           \"--on-collision suffix\" to give them different names", path.display());
+                }
+                n += 1;
+                candidate = suffixed(&path, n);
+            }
+
+            let key = absolute(&candidate);
+            if self.inputs.contains(&key) {
+                bail!("output <{}> would overwrite an input", candidate.display());
+            }
+            if candidate.exists() && !self.adopted.contains(&key) {
+                match self.existing {
+                    Existing::Refuse => bail!("output <{}> already exists, use \"--force\" to overwrite it \
+                                               or \"--no-clobber\" to skip its input", candidate.display()),
+                    Existing::Skip => {
+                        info!("skipping an input, since its output <{}> already exists", candidate.display());
+                        return Ok(None);
+                    },
+                    Existing::Overwrite => (),
+                }
+            }
+            keys.push(key);
+            resolved.push(candidate);
+        }
+
+        self.claimed.extend(keys);
+        Ok(Some(resolved))
+    }
+
+    fn is_claimed(&self, path: &Path, pending: &[PathBuf]) -> bool {
+        let key = absolute(path);
+        self.claimed.contains(&key) || pending.contains(&key)
+    }
+}
+
+/// Add "-<n>" to the end of a path's file stem
+fn suffixed(path: &Path, n: usize) -> PathBuf {
+    let stem = path.file_stem().map_or("".into(), |s| s.to_string_lossy());
+    let name = match path.extension() {
+        Some(ext) => format!("{}-{}.{}", stem, n, ext.to_string_lossy()),
+        None => format!("{}-{}", stem, n),
+    };
+    path.with_file_name(name)
+}
+
+/// An absolute path to compare against other paths, which resolves links if the file (or its directory) exists
+fn absolute(path: &Path) -> PathBuf {
+    if let Ok(p) = path.canonicalize() {
+        return p;
+    }
+    let parent = path.parent().filter(|p| !p.as_os_str().is_empty()).unwrap_or_else(|| Path::new("."));
+    match (parent.canonicalize(), path.file_name()) {
+        (Ok(dir), Some(name)) => dir.join(name),
+        _ => env::current_dir().map(|d| d.join(path)).unwrap_or_else(|_| path.to_path_buf()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use utils::TempDir;
+
+    #[test]
+    fn collisions_are_suffixed_or_errors() {
+        let dir = TempDir::new("collisions");
+        let out = dir.join("out.csv");
+
+        let mut guard = OutputGuard::new(Existing::Refuse, Collision::Suffix, &[]);
+        assert_eq!(guard.claim(vec![out.clone()]).unwrap(), Some(vec![out.clone()]));
+        assert_eq!(guard.claim(vec![out.clone(), out.clone()]).unwrap(),
+                   Some(vec![suffixed(&out, 2), suffixed(&out, 3)]));
+
+        let mut guard = OutputGuard::new(Existing::Refuse, Collision::Error, &[]);
+        assert!(guard.claim(vec![out.clone()]).is_ok());
+        assert!(guard.claim(vec![out]).is_err());
+    }
+
+    #[test]
+    fn inputs_and_existing_files_are_kept() {
+        let dir = TempDir::new("existing");
+        let existing = dir.join("existing.csv");
+        ::std::fs::write(&existing, "").unwrap();
+
+        let refuse = OutputGuard::new(Existing::Refuse, Collision::Error, &[]).claim(vec![existing.clone()]);
+        let skip = OutputGuard::new(Existing::Skip, Collision::Error, &[]).claim(vec![existing.clone()]);
+        let force = OutputGuard::new(Existing::Overwrite, Collision::Error, &[]).claim(vec![existing.clone()]);
+        let input = OutputGuard::new(Existing::Overwrite, Collision::Error, ::std::slice::from_ref(&existing))
+            .claim(vec![existing.clone()]);
+
+        assert!(refuse.is_err());
+        assert_eq!(skip.unwrap(), None);
+        assert_eq!(force.unwrap(), Some(vec![existing]));
+        assert!(input.is_err());
+    }
+}
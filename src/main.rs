@@ -1,48 +1,333 @@
-#[macro_use] extern crate structopt;
+// failure_derive generates its trait impls inside of anonymous constants
+#![allow(non_local_definitions)]
+
+extern crate structopt;
 #[macro_use] extern crate serde_derive;
 #[macro_use] extern crate failure;
 #[macro_use] extern crate log;
 extern crate flexi_logger;
 extern crate serde;
+extern crate serde_json;
 extern crate calamine;
 extern crate csv;
 extern crate walkdir;
+extern crate rust_xlsxwriter;
+extern crate chrono;
+extern crate rayon;
+extern crate notify;
+extern crate sha2;
+extern crate toml;
+#[cfg(feature = "arrow")] extern crate arrow_array;
+#[cfg(feature = "arrow")] extern crate arrow_schema;
+#[cfg(feature = "arrow")] extern crate arrow_ipc;
+#[cfg(feature = "arrow")] extern crate parquet;
+#[cfg(feature = "sqlite")] extern crate rusqlite;
 
 mod sd3;
 mod mifc;
 mod si;
+mod workbook;
+mod validate;
+mod report;
+mod output;
+mod naming;
+mod guard;
+mod watch;
+mod manifest;
+mod provenance;
+mod config;
+mod template;
+#[cfg(feature = "arrow")] mod columnar;
+#[cfg(feature = "sqlite")] mod sqlite;
 #[cfg(test)] mod utils;
 
 use failure::{Error, ResultExt};
 use structopt::StructOpt;
-use calamine::{Reader, RangeDeserializerBuilder, open_workbook_auto};
+use structopt::clap::ArgMatches;
 use flexi_logger::{Logger, default_format};
 use walkdir::WalkDir;
+use rayon::prelude::*;
 use std::path::{Path, PathBuf};
-use std::fmt;
-use std::fs::{OpenOptions, self};
-use std::ffi::{OsStr};
-use sd3::SD3;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::str::FromStr;
+use std::time::Duration;
+use sd3::{SD3, SD3Error, InvalidPolicy, Outcome};
+use si::{SIError, SIUnit};
+use report::Summary;
+use output::{Destination, Format, Writer};
+use naming::{Naming, NameTemplate};
+use workbook::{InputFormat, Sheet};
+use guard::{Collision, Existing, OutputGuard};
+use manifest::Manifest;
+use config::Config;
 
 #[derive(StructOpt, Debug)]
-/// Read an SD3 (MIFC + normalization info) excel workbook and create one normalized MIFC CSV for each sheet
+/// Read an SD3 (MIFC + normalization info) excel workbook and create one normalized MIFC CSV for each sheet.
+/// Without a subcommand, the INPUTs are normalized as with "normalize"
 struct Opt {
-    /// Any number of input sd3-formatted excel files or directories containing excel files
+    /// Print debug info based on the number of "v"s passed
+    #[structopt(short = "v", parse(from_occurrences), raw(global = "true"))]
+    verbose: usize,
+    /// Read defaults for the options from a TOML file, instead of from "sd3norm.toml" in the working directory
+    #[structopt(long = "config", parse(from_os_str), raw(global = "true"))]
+    config: Option<PathBuf>,
+    #[structopt(flatten)]
+    normalize: Normalize,
+    #[structopt(subcommand)]
+    cmd: Option<Command>,
+}
+
+/// The inputs to read, and how to treat their rows
+#[derive(StructOpt, Debug, Clone, PartialEq)]
+struct InputOpt {
+    /// Any number of input sd3-formatted excel files or directories containing excel files.
+    /// "-" reads one excel workbook or CSV file from stdin
     #[structopt(name = "INPUT", parse(from_os_str))]
     input: Vec<PathBuf>,
+    /// How to read INPUT files: "auto" tells from each file's extension (.xlsx, .xlsm, .xls, .xlsb, .ods, .csv,
+    /// .tsv or .tab), otherwise every file is read as an "excel" (or OpenDocument) workbook, "csv" or "tsv"
+    #[structopt(long = "input-format", default_value = "auto",
+                raw(possible_values = r#"&["auto", "excel", "csv", "tsv"]"#))]
+    input_format: InputFormat,
+    /// Only read files with these comma-separated extensions when searching INPUT directories, e.g. "xlsx,ods"
+    #[structopt(long = "extensions", use_delimiter = true, parse(try_from_str = "parse_extension"))]
+    extensions: Vec<String>,
+    /// What to do with rows that have non-physical normalization info, like a zero cell count or duration:
+    /// "reject" them, "flag" them with an empty Value, or "pass" their original Value through
+    #[structopt(long = "on-invalid", default_value = "reject",
+                raw(possible_values = r#"&["reject", "flag", "pass"]"#))]
+    on_invalid: InvalidPolicy,
+}
+
+/// The options of a normalizing run
+#[derive(StructOpt, Debug, Clone, PartialEq)]
+struct Normalize {
+    #[structopt(flatten)]
+    inputs: InputOpt,
     /// Append to INPUT for output, defaults to "normalized"
     #[structopt(short = "a", long = "append")]
     append: Option<String>,
-    /// Print debug info based on the number of "v"s passed
-    #[structopt(short = "v", parse(from_occurrences))]
-    verbose: usize,
+    /// Name output files with a template instead, e.g. "{stem}_{sheet}_{date}.{ext}". The placeholders are
+    /// {stem} (of the input file), {sheet}, {sheet_index} (from 1), {date} (today), {append} and {ext}
+    #[structopt(long = "name-template")]
+    name_template: Option<NameTemplate>,
     /// Directory to create output file(s) in
     #[structopt(short = "d", long = "out-dir", parse(from_os_str))]
-    out_dir: Option<PathBuf>, 
+    out_dir: Option<PathBuf>,
+    /// Write every output straight into "--out-dir", instead of into the same subdirectories
+    /// that its workbook was found in under an INPUT directory
+    #[structopt(long = "flatten")]
+    flatten: bool,
+    /// Mirror INPUT subdirectories under "--out-dir", even if "flatten" is set in the config file
+    #[structopt(long = "no-flatten", conflicts_with = "flatten")]
+    no_flatten: bool,
+    /// Output format: one "csv" for each sheet, or one "xlsx" workbook for each input workbook
+    /// with a worksheet for each sheet, and any rows that could not be normalized.
+    /// "json", "ndjson", "parquet" and "arrow" (IPC file) write every row of a workbook into one file,
+    /// with the sheet and row it came from
+    #[structopt(short = "f", long = "format", default_value = "csv",
+                raw(possible_values = r#"&["csv", "xlsx", "json", "ndjson", "parquet", "arrow"]"#))]
+    format: Format,
+    /// Write every normalized row into a table of this SQLite database, instead of into output files.
+    /// Rows already in the database with the same Chip ID, Target, Sample Location, time and Replicate are replaced
+    #[structopt(long = "sqlite", parse(from_os_str))]
+    sqlite: Option<PathBuf>,
+    /// Combine the normalized rows of every input into this one file, with "Source File" and "Source Sheet"
+    /// columns. Its format is taken from its extension, or from "--format" if that isn't one of the output formats
+    #[structopt(long = "merge", parse(from_os_str), conflicts_with = "sqlite")]
+    merge: Option<PathBuf>,
+    /// Write the normalized rows of every input to stdout in the "--format" format, like "--merge" does to a file
+    #[structopt(long = "stdout", raw(conflicts_with_all = r#"&["merge", "sqlite", "json_summary"]"#))]
+    stdout: bool,
+    /// Write output files, even if "stdout" is set in the config file
+    #[structopt(long = "no-stdout", conflicts_with = "stdout")]
+    no_stdout: bool,
+    /// Overwrite output files that already exist, instead of failing
+    #[structopt(long = "force")]
+    force: bool,
+    /// Skip the inputs whose output files already exist, instead of failing
+    #[structopt(long = "no-clobber", conflicts_with = "force")]
+    no_clobber: bool,
+    /// What to do when more than one output of a run would get the same file name: fail with an "error",
+    /// or "suffix" the later ones' names with "-2", "-3", ...
+    #[structopt(long = "on-collision", default_value = "error", raw(possible_values = r#"&["error", "suffix"]"#))]
+    on_collision: Collision,
+    /// Fail on the first row or sheet that could not be normalized, instead of skipping it
+    #[structopt(long = "strict")]
+    strict: bool,
+    /// Skip the rows and sheets that could not be normalized, even if "strict" is set in the config file
+    #[structopt(long = "no-strict", conflicts_with = "strict")]
+    no_strict: bool,
+    /// Skip the inputs that haven't changed since an earlier "--incremental" run with the same options, whose
+    /// outputs are all still there. Their hashes are kept in ".sd3norm-manifest.json" in the output directory
+    #[structopt(long = "incremental", raw(conflicts_with_all = r#"&["merge", "stdout"]"#))]
+    incremental: bool,
+    /// Normalize every input, even if "incremental" is set in the config file
+    #[structopt(long = "no-incremental", conflicts_with = "incremental")]
+    no_incremental: bool,
+    /// Write a JSON record of the run for audits, with the options, the inputs and outputs with their SHA-256 hashes,
+    /// the rows of each sheet, and the unit conversion factors used, into the output directory
+    #[structopt(long = "provenance")]
+    provenance: bool,
+    /// Don't write a provenance record, even if "provenance" is set in the config file
+    #[structopt(long = "no-provenance", conflicts_with = "provenance")]
+    no_provenance: bool,
+    /// Stop at the first workbook that can't be read or normalized, instead of going on to the rest
+    #[structopt(long = "fail-fast")]
+    fail_fast: bool,
+    /// Go on past failed workbooks, even if "fail-fast" is set in the config file
+    #[structopt(long = "no-fail-fast", conflicts_with = "fail_fast")]
+    no_fail_fast: bool,
+    /// Number of workbooks to read and normalize at the same time, defaults to the number of CPUs
+    #[structopt(short = "j", long = "jobs")]
+    jobs: Option<usize>,
+    /// Print each output file that would be written, and how many rows it would get, without writing anything
+    #[structopt(long = "dry-run", conflicts_with = "json_summary")]
+    dry_run: bool,
+    /// Print the end-of-run summary to stdout as JSON, instead of as a table to stderr
+    #[structopt(long = "json-summary")]
+    json_summary: bool,
+    /// Print the summary as a table, even if "json-summary" is set in the config file
+    #[structopt(long = "no-json-summary", conflicts_with = "json_summary")]
+    no_json_summary: bool,
+}
+
+impl Normalize {
+    /// The options of a run that is given none
+    fn defaults() -> Self {
+        Normalize::from_clap(&Normalize::clap().get_matches_from(["sd3norm"]))
+    }
+}
+
+#[derive(StructOpt, Debug)]
+enum Command {
+    /// Normalize the INPUTs, which is also what is done without a subcommand
+    #[structopt(name = "normalize")]
+    Normalize(Normalize),
+    /// Check SD3 workbooks for rows that would not be normalized, without writing any output
+    #[structopt(name = "validate")]
+    Validate {
+        #[structopt(flatten)]
+        inputs: InputOpt,
+        /// Print the found problems as JSON
+        #[structopt(long = "json")]
+        json: bool,
+    },
+    /// Count the sheets and rows of SD3 workbooks, and what normalizing would do with them, without writing any output
+    #[structopt(name = "summarize")]
+    Summarize {
+        #[structopt(flatten)]
+        inputs: InputOpt,
+        /// Print the counts as JSON
+        #[structopt(long = "json")]
+        json: bool,
+    },
+    /// Write a blank SD3 workbook, with every column that sd3norm reads, to fill in
+    #[structopt(name = "template")]
+    Template {
+        /// The file to write, an excel workbook unless it ends in ".csv"
+        #[structopt(name = "OUTPUT", parse(from_os_str), default_value = "sd3-template.xlsx")]
+        output: PathBuf,
+        /// Overwrite OUTPUT if it already exists
+        #[structopt(long = "force")]
+        force: bool,
+    },
+    /// List the units that values can be in, or convert a value between them
+    #[structopt(name = "units")]
+    Units(UnitsCommand),
+    /// Watch a directory, and normalize each workbook that is added to it or changed, once it stops changing.
+    /// Outputs are written with the options given before "watch", e.g. "sd3norm -d out -f xlsx watch drop/"
+    #[structopt(name = "watch")]
+    Watch {
+        /// Directory to watch, along with its subdirectories
+        #[structopt(name = "DIR", parse(from_os_str))]
+        dir: PathBuf,
+        /// Seconds that a file must go without changing before it is normalized
+        #[structopt(long = "debounce", default_value = "2")]
+        debounce: u64,
+        /// File that records what has been normalized, so a restarted watch skips unchanged workbooks.
+        /// Defaults to ".sd3norm-watch.json" in DIR
+        #[structopt(long = "state", parse(from_os_str))]
+        state: Option<PathBuf>,
+    },
+    /// Inspect the options read from a config file
+    #[structopt(name = "config")]
+    Config(ConfigCommand),
+}
+
+#[derive(StructOpt, Debug)]
+enum UnitsCommand {
+    /// List every unit that can be read, grouped by what they measure, with the other names they are read from
+    #[structopt(name = "list")]
+    List,
+    /// Convert a value from one unit to another of the same type, e.g. "convert 150 ng/mL pg/uL"
+    #[structopt(name = "convert", raw(setting = "structopt::clap::AppSettings::AllowNegativeNumbers"))]
+    Convert {
+        #[structopt(name = "VALUE")]
+        value: f64,
+        #[structopt(name = "FROM", parse(try_from_str = "parse_unit"))]
+        from: SIUnit,
+        #[structopt(name = "TO", parse(try_from_str = "parse_unit"))]
+        to: SIUnit,
+    },
+}
+
+#[derive(StructOpt, Debug)]
+enum ConfigCommand {
+    /// Print the options that a run would use, after the config file and command line are combined, as TOML
+    #[structopt(name = "show")]
+    Show,
+}
+
+/// Pairs of options that can't be used together. A config file's option is ignored when the other one
+/// is given on the command line
+const CONFLICTS: [(&str, &str); 8] = [
+    ("force", "no_clobber"),
+    ("merge", "sqlite"),
+    ("stdout", "merge"),
+    ("stdout", "sqlite"),
+    ("stdout", "json_summary"),
+    ("incremental", "merge"),
+    ("incremental", "stdout"),
+    ("dry_run", "json_summary"),
+];
+
+/// Process exit codes, so that scripts can tell how a run went
+mod exit {
+    /// Every row was normalized or deliberately excluded
+    pub const OK: i32 = 0;
+    /// A workbook could not be read or normalized, or an output could not be written
+    pub const WORKBOOK_FAILED: i32 = 1;
+    /// The command line arguments were not valid
+    pub const USAGE: i32 = 2;
+    /// Some rows or sheets could not be normalized, and were skipped
+    pub const ROWS_SKIPPED: i32 = 3;
 }
 
 fn main() {
-    let opts = Opt::from_args();
+    let matches = match Opt::clap().get_matches_safe() {
+        Ok(m) => m,
+        Err(e) => match e.kind {
+            structopt::clap::ErrorKind::HelpDisplayed | structopt::clap::ErrorKind::VersionDisplayed
+                => e.exit(),
+            _ => {
+                eprintln!("{}", e.message);
+                ::std::process::exit(exit::USAGE);
+            },
+        },
+    };
+    let mut opts = Opt::from_clap(&matches);
+    let config = check_order(&opts)
+        .and_then(|_| load_config(&mut opts, &matches))
+        .and_then(|path| check_requirements(&opts).map(|_| path));
+    let config = match config {
+        Ok(path) => path,
+        Err(e) => {
+            eprintln!("error: {}", report::describe(&e));
+            ::std::process::exit(exit::USAGE);
+        },
+    };
     let log_level = match opts.verbose {
         0 => "error",
         1 => "info",
@@ -55,211 +340,1150 @@ fn main() {
         .start()
         .unwrap_or_else(|e| panic!("Logger initialization failed with {}",e) );
 
-    if let Err(e) = run(opts) {
-        print_err(&e);
-        match ::std::env::var("RUST_BACKTRACE").as_ref().map(|s| s.as_str()) {
-            Ok("1") => error!("Backtrace:\n{}", e.backtrace()),
-            _ => (),
+    let result = match opts.cmd {
+        None => run(&opts.normalize, opts.verbose),
+        Some(Command::Normalize(ref normalize)) => run(normalize, opts.verbose),
+        Some(Command::Validate { ref inputs, json }) => run_validate(inputs, json),
+        Some(Command::Summarize { ref inputs, json }) => run_summarize(inputs, json),
+        Some(Command::Template { ref output, force }) => run_template(output, force),
+        Some(Command::Units(ref units)) => run_units(units),
+        Some(Command::Watch { ref dir, debounce, ref state }) =>
+            run_watch(&opts.normalize, opts.verbose, dir, Duration::from_secs(debounce), state.as_deref()),
+        Some(Command::Config(ConfigCommand::Show)) => show_config(&opts, config.as_deref()),
+    };
+
+    match result {
+        Ok(code) => ::std::process::exit(code),
+        Err(e) => {
+            print_err(&e);
+            if let Ok("1") = ::std::env::var("RUST_BACKTRACE").as_ref().map(|s| s.as_str()) {
+                error!("Backtrace:\n{}", e.backtrace());
+            }
+            ::std::process::exit(exit::WORKBOOK_FAILED);
         }
-        ::std::process::exit(1);
     }
 }
 
-fn run(opts: Opt) -> Result<(), Error> {
-    let inputs = opts.input;    /* A possible mixed collection of directories and file paths */
-    let output_directory = opts.out_dir.as_ref().map(PathBuf::as_path);
+fn run(opts: &Normalize, verbose: usize) -> Result<i32, Error> {
+    let mut summary = Summary::default();
+    let result = normalize_inputs(opts, verbose, &mut summary);
+
+    /* Report on everything that was done, even if the run stopped early */
+    if opts.json_summary {
+        println!("{}", serde_json::to_string_pretty(&summary)?);
+    } else {
+        eprint!("{}", summary);
+    }
+    result?;
+
+    if !summary.failures.is_empty() {
+        bail!("{} workbook(s) could not be normalized", summary.failures.len());
+    } else if summary.total_rejected() + summary.sheets_skipped > 0 {
+        Ok(exit::ROWS_SKIPPED)
+    } else {
+        Ok(exit::OK)
+    }
+}
+
+/// Normalize every workbook in the INPUTs of `opts`, counting what happened in `summary`. A workbook that fails is
+/// only listed in `summary`, unless the run stopped at it without normalizing the rest
+fn normalize_inputs(opts: &Normalize, verbose: usize, summary: &mut Summary) -> Result<(), Error> {
+    let inputs = &opts.inputs.input;    /* A possible mixed collection of directories and file paths */
+    let output_directory = opts.out_dir.as_deref();
     /* Get the value to append to the end of the output, or use the default */
     let append_str = opts.append.as_ref().map_or("normalized", String::as_ref);
     
     /* Get output base path by appending the value of optional directory flag */
     debug!("Workbook(s) Input: {:#?}", &inputs);
     debug!("Output directory: {:?}", output_directory);
+    debug!("flatten: {}", opts.flatten);
     debug!("output append: {}", &append_str);
+    debug!("output name template: {:?}", opts.name_template);
+    debug!("output format: {:?}", opts.format);
+    debug!("non-physical rows: {:?}", opts.inputs.on_invalid);
+    debug!("strict: {}", opts.strict);
+    debug!("fail fast: {}", opts.fail_fast);
+    debug!("sqlite database: {:?}", opts.sqlite);
+    debug!("merged output: {:?}", opts.merge);
+    debug!("stdout: {}", opts.stdout);
+    debug!("dry run: {}", opts.dry_run);
+    debug!("jobs: {:?}", opts.jobs);
+    debug!("incremental: {}", opts.incremental);
+    debug!("provenance: {}", opts.provenance);
+    debug!("force: {}, no clobber: {}, on collision: {:?}", opts.force, opts.no_clobber, opts.on_collision);
 
-    /* Convert collection of input files and/or directories into a workbook path iterator */
-    let workbooks = inputs
-        .iter()
-        .flat_map(|entry| { 
-            WalkDir::new(&entry)
-            .into_iter()
-            .filter_map(|e| e.ok())
-            .map(|e| e.path().to_path_buf())
-        })
-        .filter(is_excel)
-        .filter(is_not_excel_temp)
-        .map(|wb| {
-            let out = generate_output_base(&wb, output_directory);
-            (wb, out, &append_str)
-        });
-    // TODO: Use a parallel iterator? 
-    for (wb, out, app) in workbooks {
-        match out {
-            Ok(out) => normalize_workbook(&wb, &out, &app)?,
-            Err(e) => {
-                warn!("Couldn't generate an output for workbook <{}> due to:\n{}", wb.display(), e);
-                continue;
+    /* Outputs may never replace an input, so every input is found before anything is written */
+    let workbooks: Vec<_> = find_workbooks(inputs, opts.inputs.input_format, &opts.inputs.extensions, true).collect();
+    let paths: Vec<_> = workbooks.iter().map(|wb| wb.path.clone()).collect();
+    let existing = match (opts.force, opts.no_clobber) {
+        (true, _) => Existing::Overwrite,
+        (false, true) => Existing::Skip,
+        (false, false) => Existing::Refuse,
+    };
+    let mut guard = OutputGuard::new(existing, opts.on_collision, &paths);
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(opts.jobs.unwrap_or(0))
+        .build()
+        .context("starting worker threads")?;
+
+    /* Inputs are hashed before they are read, for an --incremental manifest or --provenance */
+    let hashes: HashMap<PathBuf, String> = if opts.incremental || opts.provenance {
+        pool.install(|| workbooks.par_iter()
+            .filter(|found| found.path.as_os_str() != workbook::STDIN)
+            .filter_map(|found| manifest::sha256(&found.path).ok().map(|h| (found.path.clone(), h)))
+            .collect())
+    } else {
+        HashMap::new()
+    };
+
+    /* Inputs that haven't changed since an earlier --incremental run are skipped */
+    let manifest_path = output_directory.unwrap_or_else(|| Path::new(".")).join(manifest::FILE_NAME);
+    let mut manifest = if opts.incremental { Some(Manifest::load(&manifest_path, settings(opts, verbose)?)?) } else { None };
+    let workbooks: Vec<_> = match manifest {
+        Some(ref m) => workbooks.into_iter()
+            .filter(|found| match hashes.get(&found.path) {
+                Some(h) if m.is_current(&found.path, h) => {
+                    info!("skipping <{}>, which hasn't changed", found.path.display());
+                    summary.workbooks_skipped += 1;
+                    false
+                },
+                Some(_) => {
+                    guard.adopt(m.outputs(&found.path));
+                    true
+                },
+                None => true,
+            })
+            .collect(),
+        None => workbooks,
+    };
+
+    /* A single writer for every workbook, instead of output files for each */
+    let mut shared = match (&opts.sqlite, &opts.merge) {
+        (Some(db), _) if opts.dry_run => Some(output::dry_run_writer(vec![db.clone()], false)),
+        (Some(db), _) => Some(sqlite_writer(db)?),
+        (None, Some(file)) => {
+            let format = file.extension()
+                .and_then(|e| e.to_str()?.parse().ok())
+                .unwrap_or(opts.format);
+            match guard.claim(vec![file.clone()])? {
+                Some(paths) if opts.dry_run => Some(output::dry_run_writer(paths, false)),
+                Some(_) => Some(output::merged_writer(format, Destination::File(file.clone()))?),
+                None => return Ok(()),
+            }
+        },
+        (None, None) if opts.stdout && opts.dry_run
+            => Some(output::dry_run_writer(vec![Destination::Stdout.path()], false)),
+        (None, None) if opts.stdout => Some(output::merged_writer(opts.format, Destination::Stdout)?),
+        (None, None) => None,
+    };
+    let naming = Naming::new(opts.name_template.clone(), append_str);
+    /* Set when the run stops before every workbook was normalized */
+    let mut stopped = false;
+
+    /* Workbooks are read in parallel a batch at a time, so that only a few are held in memory at once. Outputs
+       are then claimed (and rows written to a shared writer) in input order, so the run's results don't depend on
+       which workbook happened to be read first */
+    for batch in workbooks.chunks(pool.current_num_threads() * 2) {
+        let opened: Vec<_> = pool.install(|| batch.par_iter()
+            .map(|found| workbook::open_sheets(&found.path, opts.inputs.input_format))
+            .collect());
+
+        let mut planned = Vec::new();
+        for (found, sheets) in batch.iter().zip(opened) {
+            let wb = &found.path;
+            let sheets = match sheets {
+                Ok(s) => s,
+                Err(e) => {
+                    workbook_failed(wb, &e, summary);
+                    remember(&mut manifest, &hashes, wb, None);
+                    if opts.fail_fast { stopped = true; break; }
+                    continue;
+                },
+            };
+            match shared {
+                Some(ref mut writer) => match normalize_sheets(wb, &sheets, writer.as_mut(), opts, summary) {
+                    Ok(()) => {
+                        summary.workbooks += 1;
+                        let db: Vec<_> = opts.sqlite.iter().cloned().collect();
+                        remember(&mut manifest, &hashes, wb, Some(&db));
+                    },
+                    /* Some of its rows may already have been written, so the shared output can't be finished */
+                    Err(e) => {
+                        workbook_failed(wb, &e, summary);
+                        stopped = true;
+                        break;
+                    },
+                },
+                None => {
+                    let out = generate_output_base(found, output_directory, opts.flatten, opts.format);
+                    let paths = output::paths(opts.format, &out, &naming, &sheets);
+                    match guard.claim(paths) {
+                        Ok(Some(paths)) => planned.push((wb.clone(), sheets, paths)),
+                        Ok(None) => summary.workbooks_skipped += 1,
+                        Err(e) => {
+                            workbook_failed(wb, &e.context("writing its outputs").into(), summary);
+                            remember(&mut manifest, &hashes, wb, None);
+                            if opts.fail_fast { stopped = true; break; }
+                        },
+                    }
+                },
+            }
+        }
+
+        let write = |(wb, sheets, paths): (PathBuf, Vec<Sheet>, Vec<PathBuf>)| {
+            let mut s = Summary::default();
+            let result = normalize_workbook(&wb, &sheets, paths, opts, &mut s);
+            (wb, s, result)
+        };
+        /* A dry run prints its planned outputs as it goes, so they are kept in order */
+        let written: Vec<_> = if opts.dry_run {
+            planned.into_iter().map(write).collect()
+        } else {
+            pool.install(|| planned.into_par_iter().map(write).collect())
+        };
+        for (wb, s, result) in written {
+            summary.add(&s);
+            match result {
+                Ok(()) => {
+                    summary.workbooks += 1;
+                    remember(&mut manifest, &hashes, &wb, Some(&s.outputs));
+                },
+                Err(e) => {
+                    workbook_failed(&wb, &e, summary);
+                    remember(&mut manifest, &hashes, &wb, None);
+                    stopped |= opts.fail_fast;
+                },
+            }
+        }
+        if stopped { break; }
+    }
+
+    let mut result = if stopped {
+        Err(format_err!("stopped at the first workbook that could not be normalized"))
+    } else {
+        Ok(())
+    };
+    /* Only keep the rows of the workbooks that failed, if any, when they were all written */
+    if let (Some(writer), false) = (shared, stopped) {
+        result = result.and(finish(writer, summary));
+    }
+    if let (Some(m), false) = (manifest, opts.dry_run) {
+        result = result.and(create_parent_dirs(::std::slice::from_ref(&manifest_path)).and(m.save(&manifest_path)));
+    }
+    if opts.provenance && !opts.dry_run {
+        /* Next to the outputs, if they are all in one place */
+        let dir = output_directory
+            .or_else(|| opts.merge.as_ref().or(opts.sqlite.as_ref()).and_then(|f| f.parent()))
+            .filter(|d| !d.as_os_str().is_empty())
+            .unwrap_or_else(|| Path::new("."));
+        let inputs: Vec<_> = workbooks.iter()
+            .map(|found| provenance::Hashed { path: found.path.clone(), sha256: hashes.get(&found.path).cloned() })
+            .collect();
+        let written = fs::create_dir_all(dir).map_err(Error::from)
+            .and_then(|_| option_values(opts, verbose))
+            .and_then(|options| provenance::write(dir, &options, &inputs, summary));
+        result = result.and(written.map(|_| ()));
+    }
+    result
+}
+
+/// Check that nothing on the command line comes before a subcommand that would ignore it
+fn check_order(opts: &Opt) -> Result<(), Error> {
+    let before = &opts.normalize;
+    match opts.cmd {
+        None => Ok(()),
+        /* An INPUT named like a subcommand would otherwise start it, and the INPUTs before it would be lost */
+        Some(_) if !before.inputs.input.is_empty() =>
+            bail!("INPUTs can't come before a subcommand, pass them after \"--\" if one is named like it"),
+        /* Only "watch" and "config show" use the normalizing options given before them */
+        Some(Command::Watch { .. }) | Some(Command::Config(_)) => Ok(()),
+        Some(_) if *before != Normalize::defaults() =>
+            bail!("options for normalizing can only come before \"watch\" and \"config\", \
+                   pass them after the subcommand"),
+        Some(_) => Ok(()),
+    }
+}
+
+/// Fill in the options that weren't given on the command line from the config file, returning its path if there is one
+fn load_config(opts: &mut Opt, matches: &ArgMatches) -> Result<Option<PathBuf>, Error> {
+    let (path, config) = match Config::find(opts.config.as_deref())? {
+        Some(found) => found,
+        None => return Ok(None),
+    };
+    let in_config = |e: Error| e.context(format!("in config <{}>", path.display()));
+
+    /* A global option can be given before or after the subcommand */
+    let sub = matches.subcommand().1;
+    if matches.occurrences_of("verbose") + sub.map_or(0, |m| m.occurrences_of("verbose")) == 0 {
+        opts.verbose = config.verbose.unwrap_or(opts.verbose);
+    }
+    match opts.cmd {
+        Some(Command::Normalize(ref mut normalize)) => {
+            apply_config(normalize, sub.unwrap_or(matches), &config).map_err(in_config)?;
+            check_conflicts(normalize, &path)?;
+        },
+        Some(Command::Validate { ref mut inputs, .. }) | Some(Command::Summarize { ref mut inputs, .. }) =>
+            apply_input_config(inputs, sub.unwrap_or(matches), &config).map_err(in_config)?,
+        Some(Command::Template { .. }) | Some(Command::Units(_)) => (),
+        None | Some(Command::Watch { .. }) | Some(Command::Config(_)) => {
+            apply_config(&mut opts.normalize, matches, &config).map_err(in_config)?;
+            check_conflicts(&opts.normalize, &path)?;
+        },
+    }
+    Ok(Some(path))
+}
+
+/// Check that a config file didn't set options that can't be used together
+fn check_conflicts(opts: &Normalize, path: &Path) -> Result<(), Error> {
+    for &(a, b) in &CONFLICTS {
+        if is_set(opts, a) && is_set(opts, b) {
+            bail!("\"--{}\" and \"--{}\" can't be used together, but one of them is set in config <{}>",
+                a.replace('_', "-"), b.replace('_', "-"), path.display());
+        }
+    }
+    Ok(())
+}
+
+/// Check that the options an option needs are set, once the config file has been applied,
+/// so that either one can be given on the command line or in the config file
+fn check_requirements(opts: &Opt) -> Result<(), Error> {
+    let normalize = match opts.cmd {
+        Some(Command::Normalize(ref normalize)) => normalize,
+        None | Some(Command::Watch { .. }) | Some(Command::Config(_)) => &opts.normalize,
+        _ => return Ok(()),
+    };
+    if normalize.flatten && normalize.out_dir.is_none() {
+        bail!("\"--flatten\" needs an \"--out-dir\", on the command line or in the config file");
+    }
+    if let Some(Command::Watch { .. }) = opts.cmd {
+        if normalize.merge.is_some() || normalize.stdout {
+            bail!("\"watch\" writes outputs for each workbook, or to \"--sqlite\", \
+                   so it can't use \"--merge\" or \"--stdout\"");
+        }
+    }
+    let shared = normalize.stdout || normalize.merge.is_some() || normalize.sqlite.is_some();
+    if !shared && normalize.inputs.input.iter().any(|i| i.as_os_str() == workbook::STDIN) {
+        bail!("there is no output file name for stdin (\"-\"), so use \"--stdout\", \"--merge\" or \"--sqlite\"");
+    }
+    Ok(())
+}
+
+/// Set the option `$field` of `$opts` to its `$value` in the config file, unless it was given on the command line
+macro_rules! fill {
+    ($opts:ident, $config:ident, $overridden:ident, $field:ident, |$v:ident| $value:expr) => {
+        if let Some(ref $v) = $config.$field {
+            if !$overridden(stringify!($field)) {
+                $opts.$field = $value;
             }
         }
+    };
+}
+
+/// Parse an option's value in the config file
+fn parse_value<T: FromStr<Err = Error>>(value: &str, option: &str) -> Result<T, Error> {
+    value.parse().map_err(|e: Error| e.context(format!("reading \"{}\"", option)).into())
+}
+
+/// Set each input option of `opts` that is in `config`, and wasn't given on the command line
+fn apply_input_config(opts: &mut InputOpt, matches: &ArgMatches, config: &Config) -> Result<(), Error> {
+    let given = |arg: &str| matches.occurrences_of(arg) > 0;
+    if let Some(ref input) = config.input {
+        if !given("INPUT") {
+            opts.input = input.clone();
+        }
     }
+    fill!(opts, config, given, input_format, |v| parse_value(v, "input-format")?);
+    fill!(opts, config, given, extensions, |v| v.iter().map(|e| parse_extension(e)).collect::<Result<_, _>>()?);
+    fill!(opts, config, given, on_invalid, |v| parse_value(v, "on-invalid")?);
+    Ok(())
+}
+
+/// Set each option of `opts` that is in `config` and wasn't given on the command line (nor was its "--no-" flag,
+/// or an option that conflicts with it)
+fn apply_config(opts: &mut Normalize, matches: &ArgMatches, config: &Config) -> Result<(), Error> {
+    apply_input_config(&mut opts.inputs, matches, config)?;
+    let given = |arg: &str| matches.occurrences_of(arg) > 0;
+    let overridden = |arg: &str| given(arg) || given(&format!("no_{}", arg))
+        || CONFLICTS.iter().any(|&(a, b)| (a == arg && given(b)) || (b == arg && given(a)));
 
+    fill!(opts, config, overridden, append, |v| Some(v.clone()));
+    fill!(opts, config, overridden, name_template, |v| Some(parse_value(v, "name-template")?));
+    fill!(opts, config, overridden, out_dir, |v| Some(v.clone()));
+    fill!(opts, config, overridden, flatten, |v| *v);
+    fill!(opts, config, overridden, format, |v| parse_value(v, "format")?);
+    fill!(opts, config, overridden, sqlite, |v| Some(v.clone()));
+    fill!(opts, config, overridden, merge, |v| Some(v.clone()));
+    fill!(opts, config, overridden, stdout, |v| *v);
+    fill!(opts, config, overridden, force, |v| *v);
+    fill!(opts, config, overridden, no_clobber, |v| *v);
+    fill!(opts, config, overridden, on_collision, |v| parse_value(v, "on-collision")?);
+    fill!(opts, config, overridden, strict, |v| *v);
+    fill!(opts, config, overridden, incremental, |v| *v);
+    fill!(opts, config, overridden, provenance, |v| *v);
+    fill!(opts, config, overridden, fail_fast, |v| *v);
+    fill!(opts, config, overridden, jobs, |v| Some(*v));
+    fill!(opts, config, overridden, json_summary, |v| *v);
     Ok(())
 }
 
-fn normalize_workbook<P, O>(wb_path: P, output_base: O, append: &str) -> Result<(), Error>
-where P: AsRef<Path> + fmt::Debug,
-      O: AsRef<Path> + fmt::Debug
+/// Check if one of the `CONFLICTS` options is set
+fn is_set(opts: &Normalize, arg: &str) -> bool {
+    match arg {
+        "force" => opts.force,
+        "no_clobber" => opts.no_clobber,
+        "merge" => opts.merge.is_some(),
+        "sqlite" => opts.sqlite.is_some(),
+        "stdout" => opts.stdout,
+        "json_summary" => opts.json_summary,
+        "incremental" => opts.incremental,
+        "dry_run" => opts.dry_run,
+        _ => false,
+    }
+}
+
+/// The options that a run would use, in the form of a config file
+fn effective_config(opts: &Normalize, verbose: usize) -> Config {
+    Config {
+        input: Some(opts.inputs.input.clone()),
+        input_format: Some(opts.inputs.input_format.to_string()),
+        extensions: Some(opts.inputs.extensions.clone()),
+        append: Some(opts.append.clone().unwrap_or_else(|| "normalized".to_string())),
+        name_template: opts.name_template.as_ref().map(|t| t.to_string()),
+        verbose: Some(verbose),
+        out_dir: opts.out_dir.clone(),
+        flatten: Some(opts.flatten),
+        format: Some(opts.format.to_string()),
+        on_invalid: Some(opts.inputs.on_invalid.to_string()),
+        sqlite: opts.sqlite.clone(),
+        merge: opts.merge.clone(),
+        stdout: Some(opts.stdout),
+        force: Some(opts.force),
+        no_clobber: Some(opts.no_clobber),
+        on_collision: Some(opts.on_collision.to_string()),
+        strict: Some(opts.strict),
+        incremental: Some(opts.incremental),
+        provenance: Some(opts.provenance),
+        fail_fast: Some(opts.fail_fast),
+        jobs: opts.jobs,
+        json_summary: Some(opts.json_summary),
+    }
+}
+
+fn show_config(opts: &Opt, path: Option<&Path>) -> Result<i32, Error> {
+    match path {
+        Some(p) => println!("# Read from <{}>, and the command line", p.display()),
+        None => println!("# No config file was found, so these are from the command line and defaults"),
+    }
+    print!("{}", effective_config(&opts.normalize, opts.verbose).to_toml()?);
+    Ok(exit::OK)
+}
+
+/// The value of every option of a run, for its provenance manifest. They are named and written as they are
+/// in a config file, along with "dry-run", which can't be set in one
+fn option_values(opts: &Normalize, verbose: usize) -> Result<BTreeMap<String, String>, Error> {
+    fn text(value: &serde_json::Value) -> String {
+        match *value {
+            serde_json::Value::Null => String::new(),
+            serde_json::Value::String(ref s) => s.clone(),
+            serde_json::Value::Array(ref values) => values.iter().map(text).collect::<Vec<_>>().join(", "),
+            ref v => v.to_string(),
+        }
+    }
+
+    let config = serde_json::to_value(effective_config(opts, verbose))?;
+    let mut values: BTreeMap<_, _> = config.as_object().into_iter().flatten()
+        .map(|(option, value)| (option.clone(), text(value)))
+        .collect();
+    values.insert("dry-run".to_string(), opts.dry_run.to_string());
+    Ok(values)
+}
+
+/// The options that change what is written for an input, which an "--incremental" run must match to skip it
+fn settings(opts: &Normalize, verbose: usize) -> Result<manifest::Settings, Error> {
+    const OUTPUT_OPTIONS: [&str; 10] = [
+        "input-format", "append", "name-template", "out-dir", "flatten", "format", "on-invalid", "sqlite",
+        "on-collision", "strict",
+    ];
+    Ok(manifest::Settings {
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        options: option_values(opts, verbose)?.into_iter()
+            .filter(|(k, _)| OUTPUT_OPTIONS.contains(&k.as_str()))
+            .collect(),
+    })
+}
+
+/// Record the `outputs` of a workbook in an "--incremental" manifest, or forget it if it failed
+fn remember(manifest: &mut Option<Manifest>, hashes: &HashMap<PathBuf, String>, wb: &Path,
+            outputs: Option<&[PathBuf]>)
 {
-    let mut workbook = open_workbook_auto(&wb_path)
-        .context(format!("opening excel workbook <{:?}>", &wb_path))?;
-    /* Iterate over the sheets in a workbook */
-    let sheets = workbook.sheet_names().to_vec();
-    let sheet_sum = sheets.len();
-
-    for (i, s) in sheets.iter().enumerate() {
-        let sheet = workbook.worksheet_range(s).unwrap()?;
-
-        /* Generate a writer to output the normalized values from this sheet 
-         * If there is only one sheet, don't append the sheet name to the output file name
-        **/
-        let output = {
-            let mut out = output_base.as_ref().to_path_buf();
-            let add_sheet = sheet_sum > 1;
-            let appended_info = format!("{s_h}{s}-{a}", 
-                s_h = if add_sheet {"-"} else {""},
-                s = if add_sheet {s} else {""},
-                a =  append
-            );
-            append_file_name(&mut out, &appended_info);
-            out
-        };
+    if let Some(ref mut m) = *manifest {
+        match (hashes.get(wb), outputs) {
+            (Some(hash), Some(outputs)) => m.record(wb, hash.clone(), outputs),
+            _ => m.forget(wb),
+        }
+    }
+}
+
+fn run_validate(inputs: &InputOpt, json: bool) -> Result<i32, Error> {
+    debug!("Workbook(s) Input: {:#?}", inputs.input);
 
-        info!("{:?} - {} (#{}):\nOutput file: {:?}", &wb_path, s, i, &output);
+    let format = inputs.input_format;
+    let problems: Vec<_> = find_workbooks(&inputs.input, format, &inputs.extensions, false)
+        .flat_map(|wb| validate::validate_workbook(&wb.path, format, inputs.on_invalid))
+        .collect();
 
-        let mut wtr = csv::Writer::from_writer(
-            OpenOptions::new()
-                .write(true)
-                .create(true)
-                .truncate(true)
-                .open(&output)?
-        );
+    if json {
+        println!("{}", serde_json::to_string_pretty(&problems)?);
+    } else {
+        for p in &problems {
+            println!("{}", p);
+        }
+    }
+
+    if problems.is_empty() {
+        return Ok(exit::OK);
+    }
+    error!("found {} problem(s) that would prevent normalization", problems.len());
+    /* Problems without a sheet are with the workbook as a whole */
+    if problems.iter().any(|p| p.sheet.is_none()) {
+        Ok(exit::WORKBOOK_FAILED)
+    } else {
+        Ok(exit::ROWS_SKIPPED)
+    }
+}
+
+/// Count what normalizing the INPUTs would do with each of their sheets, without writing anything
+fn run_summarize(inputs: &InputOpt, json: bool) -> Result<i32, Error> {
+    /* Rows are only counted, so the options that change what is written don't matter */
+    let opts = Normalize { inputs: inputs.clone(), ..Normalize::defaults() };
+    let mut summary = Summary::default();
+
+    for found in find_workbooks(&inputs.input, inputs.input_format, &inputs.extensions, false) {
+        let wb = &found.path;
+        let counted = workbook::open_sheets(wb, inputs.input_format)
+            .and_then(|sheets| normalize_sheets(wb, &sheets, output::discard_writer().as_mut(), &opts, &mut summary));
+        match counted {
+            Ok(()) => summary.workbooks += 1,
+            Err(e) => workbook_failed(wb, &e, &mut summary),
+        }
+    }
+
+    if json {
+        #[derive(Serialize)]
+        struct Counts<'a> {
+            sheets: &'a [report::SheetRows],
+            summary: &'a Summary,
+        }
+        println!("{}", serde_json::to_string_pretty(&Counts { sheets: &summary.sheet_rows, summary: &summary })?);
+    } else {
+        for s in &summary.sheet_rows {
+            println!("{}\t{}\t{} read, {} normalized, {} flagged, {} excluded, {} rejected",
+                s.workbook.display(), s.sheet, s.read, s.normalized, s.flagged, s.excluded, s.rejected);
+        }
+        print!("{}", summary);
+    }
+
+    if !summary.failures.is_empty() {
+        Ok(exit::WORKBOOK_FAILED)
+    } else if summary.total_rejected() + summary.sheets_skipped > 0 {
+        Ok(exit::ROWS_SKIPPED)
+    } else {
+        Ok(exit::OK)
+    }
+}
+
+fn run_template(output: &Path, force: bool) -> Result<i32, Error> {
+    if output.exists() && !force {
+        bail!("<{}> already exists, use \"--force\" to overwrite it", output.display());
+    }
+    template::write(output)?;
+    info!("Template: {:?}", output);
+    Ok(exit::OK)
+}
+
+fn run_units(cmd: &UnitsCommand) -> Result<i32, Error> {
+    match *cmd {
+        UnitsCommand::List => {
+            /* The units of each type are next to each other */
+            let mut last = None;
+            for unit in &si::UNITS {
+                let kind = unit.unit_type();
+                if last != Some(kind) {
+                    if last.is_some() {
+                        println!();
+                    }
+                    let name = kind.to_string();
+                    println!("{}{} units", name[..1].to_uppercase(), &name[1..]);
+                    last = Some(kind);
+                }
+                let names = unit.names();
+                println!("{}", format!("  {:<20}{}", names[0], names[1..].join(", ")).trim_end());
+            }
+        },
+        UnitsCommand::Convert { value, from, to } => match si::convert((value, from), to) {
+            Ok(converted) => println!("{} {} = {} {}", value, from, significant(converted), to),
+            /* Like an unknown unit, units that can't be converted between are a mistake in the arguments */
+            Err(SIError::IncompatibleTypes(from_type, to_type)) => {
+                eprintln!("error: can't convert {} to {}, since {} is a {} unit and {} is a {} unit. Values can only be \
+                           converted between units of the same type, which \"sd3norm units list\" groups together",
+                          from, to, from, from_type, to, to_type);
+                return Ok(exit::USAGE);
+            },
+            Err(e) => return Err(e.into()),
+        },
+    }
+    Ok(exit::OK)
+}
+
+/// Round off the floating point error of a unit conversion, keeping 12 significant digits
+fn significant(value: f64) -> f64 {
+    format!("{:.11e}", value).parse().unwrap_or(value)
+}
+
+/// Read a unit given on the command line
+fn parse_unit(unit: &str) -> Result<SIUnit, Error> {
+    unit.parse()
+        .map_err(|_| format_err!("unknown unit <{}>, \"sd3norm units list\" shows the units that can be read", unit))
+}
+
+/// Normalize the workbooks added to or changed in `dir` as they come, with the normalization options of `opts`.
+/// Outputs of changed workbooks are overwritten, unless "--no-clobber" was passed
+fn run_watch(opts: &Normalize, verbose: usize, dir: &Path, debounce: Duration, state: Option<&Path>)
+    -> Result<i32, Error>
+{
+    let state = state.map_or_else(|| dir.join(".sd3norm-watch.json"), Path::to_path_buf);
+    let is_input = |path: &Path| {
+        opts.inputs.input_format.accepts(path, &opts.inputs.extensions)
+            && is_not_excel_temp(&path)
+            && workbook::is_sd3_text(path, opts.inputs.input_format)
+    };
+
+    watch::watch(dir, &state, debounce, is_input, |changed| normalize_batch(opts, verbose, changed))?;
+    Ok(exit::OK)
+}
+
+/// Normalize a batch of `changed` workbooks for "watch", printing its summary
+fn normalize_batch(opts: &Normalize, verbose: usize, changed: &[PathBuf]) -> watch::Normalized {
+    let inputs = InputOpt { input: changed.to_vec(), ..opts.inputs.clone() };
+    let batch = Normalize { inputs, force: !opts.no_clobber, ..opts.clone() };
+    let mut summary = Summary::default();
+    /* A run that stops early may not have normalized any of the batch */
+    let failed = match normalize_inputs(&batch, verbose, &mut summary) {
+        Ok(()) => changed.iter()
+            .filter(|p| summary.failures.iter().any(|f| f.workbook == p.display().to_string()))
+            .cloned()
+            .collect(),
+        Err(e) => {
+            print_err(&e);
+            changed.to_vec()
+        },
+    };
+    if opts.json_summary {
+        serde_json::to_string(&summary).map(|s| println!("{}", s)).unwrap_or_else(|e| error!("{}", e));
+    } else {
+        eprint!("{}", summary);
+    }
+    watch::Normalized { outputs: summary.outputs, failed }
+}
+
+#[cfg(feature = "sqlite")]
+fn sqlite_writer(db: &Path) -> Result<Box<dyn Writer>, Error> {
+    Ok(Box::new(sqlite::SqliteWriter::open(db)?))
+}
+
+#[cfg(not(feature = "sqlite"))]
+fn sqlite_writer(_db: &Path) -> Result<Box<dyn Writer>, Error> {
+    bail!("sd3norm was built without the \"sqlite\" feature, so it can't write to a database")
+}
+
+/// Normalize every sheet of a workbook into its own output files, at `paths`
+fn normalize_workbook(wb_path: &Path, sheets: &[Sheet], paths: Vec<PathBuf>, opts: &Normalize, summary: &mut Summary)
+    -> Result<(), Error>
+{
+    let mut writer = if opts.dry_run {
+        output::dry_run_writer(paths, opts.format == Format::Csv)
+    } else {
+        create_parent_dirs(&paths)?;
+        output::writer(opts.format, paths)?
+    };
+    normalize_sheets(wb_path, sheets, writer.as_mut(), opts, summary)?;
+    finish(writer, summary)
+}
+
+/// Finish writing the outputs of `writer`, or print the outputs that it would have written in a dry run
+fn finish(writer: Box<dyn Writer>, summary: &mut Summary) -> Result<(), Error> {
+    for (path, rows) in writer.planned() {
+        println!("{}\t{} rows", path.display(), rows);
+        summary.planned.push((path, rows));
+    }
+    summary.wrote(writer.finish()?);
+    Ok(())
+}
+
+/// Log why a workbook could not be normalized, and add it to the failures in `summary`
+fn workbook_failed(wb_path: &Path, e: &Error, summary: &mut Summary) {
+    error!("workbook <{}> could not be normalized", wb_path.display());
+    print_err(e);
+    summary.fail(wb_path, e);
+}
+
+/// Normalize every sheet of a workbook into `writer`, counting what happened to each row in `summary`.
+/// In `--strict` mode, a row or sheet that can't be normalized is an error instead of being skipped.
+fn normalize_sheets(wb_path: &Path, sheets: &[Sheet], writer: &mut dyn Writer, opts: &Normalize, summary: &mut Summary)
+    -> Result<(), Error>
+{
+    writer.start_workbook(wb_path)?;
+
+    /* Iterate over the sheets in a workbook */
+    for (i, sheet) in sheets.iter().enumerate() {
+        let s = &sheet.name;
+        info!("{:?} - {} (#{})", wb_path, s, i);
+        writer.start_sheet(sheet)?;
 
         /* Deserialize the data into SD3 struct, then normalize each possible row, and serialize into output*/
-        let mut rows = match RangeDeserializerBuilder::new()
-            .has_headers(true)
-            .from_range(&sheet)
-        {
+        let rows = match sheet.rows() {
             Ok(r) => r,
+            Err(e) if opts.strict => {
+                return Err(Error::from(e).context(format!("parsing sheet <{}>", s)).into());
+            },
             Err(e) => {
                 warn!("issue parsing sheet <{}>\n{}", s, e);
+                summary.sheets_skipped += 1;
                 continue;
             } 
         };
+        summary.sheets += 1;
+        let before = summary.row_counts();
 
-        for (i, result) in rows.enumerate() {
-            let record: SD3 = match result {
+        for row in rows {
+            summary.rows_read += 1;
+            let record: SD3 = match row.record {
                 Ok(r) => r,
                 Err(e) => {
-                    info!("couldn't deserializing row {} in {}:\n{}", i+2, s, e); 
+                    if opts.strict { return Err(strict_error(wb_path, e)); }
+                    info!("couldn't deserialize {}:\n{}", e.context(), e);
+                    summary.reject(e.kind());
+                    writer.reject(row.cells, &e)?;
                     continue;
                 },
             };
 
-            let normalized = match record.into_normalized() {
-                Ok(n) => n,
+            let units = record.units();
+            let normalized = match record.into_normalized(&row.context, opts.inputs.on_invalid) {
+                Ok(Outcome::Normalized(n)) => {
+                    summary.rows_normalized += 1;
+                    summary.units.extend(units);
+                    n
+                },
+                Ok(Outcome::Flagged(n)) => {
+                    summary.rows_flagged += 1;
+                    n
+                },
+                Err(SD3Error::Excluded(ctx)) => {
+                    debug!("excluded {}", ctx);
+                    summary.rows_excluded += 1;
+                    continue;
+                },
                 Err(e) => {
-                    info!("did not normalize row {} in {}:\n{}", i+2, s, e);
+                    if opts.strict { return Err(strict_error(wb_path, e)); }
+                    info!("did not normalize {}:\n{}", e.context(), e);
+                    summary.reject(e.kind());
+                    writer.reject(row.cells, &e)?;
                     continue;
                 },
             };
 
-            wtr.serialize(normalized)?;
+            writer.write(&row.context, &normalized)?;
         }
+        summary.finish_sheet(wb_path, s, before);
     }
     Ok(())
 }
 
+/// Turn a row that could not be normalized into an error for `--strict` runs
+fn strict_error(wb_path: &Path, e: SD3Error) -> Error {
+    let context = format!("{} of <{}> could not be normalized", e.context(), wb_path.display());
+    Error::from(e).context(context).into()
+}
+
 fn print_err(e: &Error) {
     error!(": {}", e);
-    for e in e.causes().skip(1) {
+    for e in e.iter_chain().skip(1) {
         error!("caused by: {}", e);
     }
 }
 
-fn append_file_name<S: AsRef<OsStr>>(path: &mut PathBuf, append: S) {
-    if path.file_name().is_some() {
-        let appended = { 
-            let mut new = path.file_stem().unwrap().to_os_string();
-            new.push(append);
-            if let Some(e) = path.extension() {
-                new.push("."); new.push(e);
-            }
-            new
+/// A workbook found in an INPUT
+struct Found {
+    path: PathBuf,
+    /// Its path inside the INPUT directory it was found in, or just its file name if it was an INPUT itself
+    relative: PathBuf,
+}
+
+impl Found {
+    fn new(path: &Path, root: &Path) -> Self {
+        let relative = match path.strip_prefix(root) {
+            Ok(r) if r.file_name().is_some() => r.to_path_buf(),
+            _ => path.file_name().map_or_else(|| path.to_path_buf(), PathBuf::from),
         };
-        path.set_file_name(appended);
-    } else {
-        path.set_file_name(append);
+        Found { path: path.to_path_buf(), relative }
     }
 }
 
-/// Read input path into a vec of PathBufs
-/*
-fn expand_dir<P: AsRef<Path>>(entry: &P) -> Vec<PathBuf>
+/// Expand a collection of input files and/or directories into the workbooks (and CSV/TSV files)
+/// they contain that can be read in `format`. With `skip_outputs`, CSV/TSV files in directories that
+/// don't have any normalization columns, like the outputs of an earlier run, are left out
+fn find_workbooks<'a>(inputs: &'a [PathBuf], format: InputFormat, extensions: &'a [String], skip_outputs: bool)
+    -> impl Iterator<Item = Found> + 'a
 {
-    WalkDir::new(entry.as_ref())
-        .into_iter()
-        .filter_map(|e| e.ok())
-        .map(|e| e.path().to_path_buf())
-        .collect()
-}
-*/
-
-/// Check the extension of a Path to see if it is an excel workbook
-fn is_excel<P: AsRef<Path>>(file: &P) -> bool {
-    if let Some(ex) = file.as_ref().extension() {
-        match &*ex.to_string_lossy() {
-            "xlsx" => true,
-            "xls" => true,
-            "xlsm" => true,
-            _ => false,
-        }
-    } else { false }
+    inputs
+        .iter()
+        .flat_map(move |entry| -> Box<dyn Iterator<Item = Found>> {
+            /* stdin, and files given with an "--input-format", are read whatever their extension is */
+            if entry.as_os_str() == workbook::STDIN || (format != InputFormat::Auto && entry.is_file()) {
+                return Box::new(::std::iter::once(Found::new(entry, entry)));
+            }
+            /* Sorting keeps the order of outputs the same from run to run, whatever order the files are listed in */
+            Box::new(WalkDir::new(entry)
+                .sort_by_file_name()
+                .into_iter()
+                .filter_map(|e| e.ok())
+                .filter(move |e| format.accepts(e.path(), extensions))
+                .filter(|e| is_not_excel_temp(&e.path()))
+                /* Files named on the command line are always read, even if they don't look like SD3 data */
+                .filter(move |e| {
+                    let skip = skip_outputs && e.depth() > 0 && !workbook::is_sd3_text(e.path(), format);
+                    if skip {
+                        info!("skipping <{}>, which has no normalization columns", e.path().display());
+                    }
+                    !skip
+                })
+                .map(move |e| Found::new(e.path(), entry)))
+        })
+}
+
+/// Check that an "--extensions" value is the extension of a file that can be read
+fn parse_extension(ext: &str) -> Result<String, Error> {
+    let ext = ext.trim().trim_start_matches('.');
+    match InputFormat::from_ext(ext) {
+        Some(_) => Ok(ext.to_lowercase()),
+        None => bail!("can't read <.{}> files, only: {}", ext, workbook::EXTENSIONS.join(", ")),
+    }
 }
 
 /// Check if an excel file is a not temp file
 fn is_not_excel_temp<P: AsRef<Path>>(file: &P) -> bool {
     !file.as_ref()
         .file_stem()
-        .filter(|s| s.to_string_lossy().starts_with("~"))
-        .is_some()
-}
-
-/// Turn the input path and the optional directory argument into an output path buffer
-fn generate_output_base(input: &Path, dir: Option<&Path>) -> Result<PathBuf, Error> {
-    if let Some(dir) = dir {
-        // Get parts of input Path to see if there is a directory structure 
-        // to attach to the output "base directory"
-        let input_filename = input.file_name()
-            .ok_or(format_err!("the input was not a file"))?;
-        let input_parent = input.parent();
-
-        // Generate output directory structure, if needed
-        let mut output = dir.to_path_buf();
-        if let Some(parent_path) = input_parent {
-            output.push(parent_path);
+        .is_some_and(|s| s.to_string_lossy().starts_with('~'))
+}
+
+/// Turn a found workbook and the optional output directory into an output path buffer. Inside the output directory,
+/// the workbook's path relative to its INPUT is mirrored, unless `flatten` is set
+fn generate_output_base(input: &Found, dir: Option<&Path>, flatten: bool, format: Format) -> PathBuf {
+    let mut output = match dir {
+        Some(dir) if flatten => dir.join(input.relative.file_name().unwrap_or_default()),
+        Some(dir) => dir.join(&input.relative),
+        None => input.path.clone(),
+    };
+    output.set_extension(format.extension());
+    output
+}
+
+/// Make the directories that output files will be written into, if needed
+fn create_parent_dirs(paths: &[PathBuf]) -> Result<(), Error> {
+    for dir in paths.iter().filter_map(|p| p.parent()).filter(|d| !d.as_os_str().is_empty()) {
+        if !dir.exists() {
+            fs::create_dir_all(dir).context(format!("making output directory <{}>", dir.display()))?;
+        } else if !dir.is_dir() {
+            bail!("output directory <{}> is not a directory", dir.display());
         }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use utils::{TempDir, sd3_csv};
 
-        if !output.exists() { 
-            fs::create_dir_all(&output).context("making output subdirectory(ies)")?;
-        } else if !output.is_dir() { 
-            bail!("Path <{:?}> passed to \"--out-dir\" is not a directory", &output);
+    #[test]
+    fn output_bases() {
+        let found = Found::new(Path::new("/data/2024/study.xlsx"), Path::new("/data"));
+        let out = Some(Path::new("out"));
+        assert_eq!(generate_output_base(&found, out, false, Format::Csv), Path::new("out/2024/study.csv"));
+        assert_eq!(generate_output_base(&found, out, true, Format::Csv), Path::new("out/study.csv"));
+        assert_eq!(generate_output_base(&found, None, false, Format::Json), Path::new("/data/2024/study.json"));
+
+        let found = Found::new(Path::new("/data/2024/study.xlsx"), Path::new("/data/2024/study.xlsx"));
+        assert_eq!(generate_output_base(&found, out, false, Format::Xlsx), Path::new("out/study.xlsx"));
+    }
+
+    #[test]
+    fn workbooks_are_found_in_name_order() {
+        let dir = TempDir::new("find");
+        fs::create_dir(dir.join("sub")).unwrap();
+        for name in &["b.xlsx", "sub/a.xlsx", "c.ods", "a.xlsx", "notes.txt"] {
+            fs::write(dir.join(name), "").unwrap();
         }
+        fs::write(dir.join("a-normalized.csv"), mifc::MIFC::HEADERS.join(",")).unwrap();
+        let find = |skip_outputs| -> Vec<_> {
+            find_workbooks(&[dir.path().to_path_buf()], InputFormat::Auto, &[], skip_outputs)
+                .map(|f| f.relative)
+                .collect()
+        };
+        assert_eq!(find(true), [Path::new("a.xlsx"), Path::new("b.xlsx"), Path::new("c.ods"), Path::new("sub/a.xlsx")]);
+        assert_eq!(find(false)[0], Path::new("a-normalized.csv"));
 
-        output.push(input_filename);
-        output.set_extension("csv");
+        let ods = vec![parse_extension(".ODS").unwrap()];
+        let found: Vec<_> = find_workbooks(&[dir.path().to_path_buf()], InputFormat::Auto, &ods, true).collect();
+        assert!(found.len() == 1 && found[0].relative == Path::new("c.ods"));
+        assert!(parse_extension("txt").is_err());
+    }
 
-        Ok(output)
-    } else {
-        Ok(input.with_extension("csv"))
+    /// Options to normalize the `input` files, replacing any earlier outputs
+    fn normalize_opts(input: Vec<PathBuf>) -> Normalize {
+        Normalize { inputs: InputOpt { input, ..Normalize::defaults().inputs }, force: true, ..Normalize::defaults() }
+    }
+
+    #[test]
+    fn strict_runs_fail_workbooks_with_rows_that_are_skipped_otherwise() {
+        let dir = TempDir::new("strict");
+        fs::write(dir.join("good.csv"), sd3_csv(&[&[]])).unwrap();
+        fs::write(dir.join("bad.csv"), sd3_csv(&[&[], &[("Sample Volume", "0")]])).unwrap();
+        let good = normalize_opts(vec![dir.join("good.csv")]);
+        let bad = normalize_opts(vec![dir.join("bad.csv")]);
+
+        assert_eq!(run(&good, 0).unwrap(), exit::OK);
+        assert_eq!(run(&Normalize { strict: true, ..good }, 0).unwrap(), exit::OK);
+        assert_eq!(run(&bad, 0).unwrap(), exit::ROWS_SKIPPED);
+        assert_eq!(fs::read_to_string(dir.join("bad-normalized.csv")).unwrap().lines().count(), 2);
+
+        fs::remove_file(dir.join("bad-normalized.csv")).unwrap();
+        assert!(run(&Normalize { strict: true, ..bad }, 0).is_err());
+        assert!(!dir.join("bad-normalized.csv").exists());
+    }
+
+    #[test]
+    fn rows_are_counted_by_what_happened_to_them() {
+        let text = sd3_csv(&[&[], &[("Exclude", "x")], &[("Sample Volume", "0")], &[("Day", "one")], &[("Value", "")]]);
+        let sheets = vec![workbook::read_delimited("study", text.as_bytes(), b',').unwrap()];
+        let count = |on_invalid| {
+            let inputs = InputOpt { on_invalid, ..Normalize::defaults().inputs };
+            let opts = Normalize { inputs, ..Normalize::defaults() };
+            let mut summary = Summary::default();
+            normalize_sheets(Path::new("study.csv"), &sheets, output::discard_writer().as_mut(), &opts, &mut summary)
+                .unwrap();
+            summary
+        };
+
+        let rejected = count(InvalidPolicy::Reject);
+        assert_eq!(rejected.sheets, 1);
+        assert_eq!(rejected.row_counts(), [5, 1, 0, 1, 3]);
+        let kinds: Vec<_> = rejected.rows_rejected.iter().map(|(k, n)| (*k, *n)).collect();
+        assert_eq!(kinds, [("Deserialize", 1), ("NoValue", 1), ("NonPositiveVolume", 1)]);
+        assert_eq!(rejected.sheet_rows.len(), 1);
+        assert_eq!(rejected.sheet_rows[0].rejected, 3);
+
+        let flagged = count(InvalidPolicy::Flag);
+        assert_eq!(flagged.row_counts(), [5, 1, 1, 1, 2]);
+    }
+
+    #[test]
+    fn failed_workbooks_dont_stop_the_others() {
+        let (input, out) = (TempDir::new("isolation-in"), TempDir::new("isolation-out"));
+        fs::write(input.join("a.csv"), sd3_csv(&[&[]])).unwrap();
+        fs::write(input.join("b.xlsx"), "not a workbook").unwrap();
+        fs::write(input.join("c.csv"), sd3_csv(&[&[("Sample Volume", "0")]])).unwrap();
+        fs::write(input.join("d.csv"), sd3_csv(&[&[]])).unwrap();
+        let opts = Normalize {
+            out_dir: Some(out.path().to_path_buf()),
+            jobs: Some(1),
+            ..normalize_opts(vec![input.path().to_path_buf()])
+        };
+        let normalize = |opts: &Normalize| {
+            let mut summary = Summary::default();
+            let result = normalize_inputs(opts, 0, &mut summary);
+            (result, summary)
+        };
+
+        let (result, summary) = normalize(&Normalize { strict: true, ..opts.clone() });
+        let failed: Vec<_> = summary.failures.iter().map(|f| f.workbook.clone()).collect();
+        assert!(result.is_ok());
+        assert_eq!(summary.workbooks, 2);
+        assert_eq!(failed, [input.join("b.xlsx").display().to_string(), input.join("c.csv").display().to_string()]);
+        assert!(out.join("a-normalized.csv").is_file() && out.join("d-normalized.csv").is_file());
+        assert!(!out.join("c-normalized.csv").exists());
+
+        fs::remove_file(out.join("a-normalized.csv")).unwrap();
+        fs::remove_file(out.join("d-normalized.csv")).unwrap();
+        let (result, summary) = normalize(&Normalize { fail_fast: true, ..opts });
+        assert!(result.is_err());
+        assert_eq!(summary.failures.len(), 1);
+        assert!(!out.join("d-normalized.csv").exists());
+    }
+
+    #[test]
+    fn watched_workbooks_fail_on_their_own() {
+        let drop = TempDir::new("watch-batch");
+        fs::write(drop.join("good.csv"), sd3_csv(&[&[]])).unwrap();
+        fs::write(drop.join("bad.xlsx"), "not a workbook").unwrap();
+        let changed = vec![drop.join("bad.xlsx"), drop.join("good.csv")];
+
+        let normalized = normalize_batch(&Normalize::defaults(), 0, &changed);
+        assert_eq!(normalized.failed, [drop.join("bad.xlsx")]);
+        assert_eq!(normalized.outputs, [drop.join("good-normalized.csv")]);
+
+        /* Stopping at the bad workbook leaves the good one unnormalized */
+        let fail_fast = Normalize { fail_fast: true, jobs: Some(1), ..Normalize::defaults() };
+        let normalized = normalize_batch(&fail_fast, 0, &changed);
+        assert_eq!(normalized.failed, changed);
     }
-}
 
+    #[test]
+    fn dry_runs_only_report_what_they_would_write() {
+        let (input, out) = (TempDir::new("dry-run-in"), TempDir::new("dry-run-out"));
+        fs::write(input.join("a.csv"), sd3_csv(&[&[], &[]])).unwrap();
+        fs::write(input.join("b.csv"), sd3_csv(&[&[], &[("Sample Volume", "0")]])).unwrap();
+        let dry_run = |opts: Normalize| {
+            let mut summary = Summary::default();
+            normalize_inputs(&Normalize { dry_run: true, ..opts }, 0, &mut summary).unwrap();
+            summary
+        };
+        let opts = Normalize {
+            out_dir: Some(out.join("sub")),
+            incremental: true,
+            provenance: true,
+            ..normalize_opts(vec![input.path().to_path_buf()])
+        };
+
+        let summary = dry_run(opts.clone());
+        assert_eq!(summary.planned, [(out.join("sub/a-normalized.csv"), 2), (out.join("sub/b-normalized.csv"), 1)]);
+        assert!(summary.outputs.is_empty());
+
+        let merge = Normalize { merge: Some(out.join("all.json")), incremental: false, ..opts };
+        assert_eq!(dry_run(merge).planned, [(out.join("all.json"), 3)]);
+        assert_eq!(fs::read_dir(out.path()).unwrap().count(), 0);
+        assert_eq!(fs::read_dir(input.path()).unwrap().count(), 2);
+    }
+
+    #[test]
+    fn validate_exit_codes() {
+        let dir = TempDir::new("validate-exit");
+        let validate = |name: &str| {
+            let inputs = InputOpt { input: vec![dir.join(name)], ..Normalize::defaults().inputs };
+            run_validate(&inputs, false).unwrap()
+        };
+        fs::write(dir.join("good.csv"), sd3_csv(&[&[]])).unwrap();
+        fs::write(dir.join("bad.csv"), sd3_csv(&[&[], &[("Sample Volume", "0")]])).unwrap();
+        fs::write(dir.join("broken.xlsx"), "not a workbook").unwrap();
+
+        assert_eq!(validate("good.csv"), exit::OK);
+        assert_eq!(validate("bad.csv"), exit::ROWS_SKIPPED);
+        assert_eq!(validate("broken.xlsx"), exit::WORKBOOK_FAILED);
+    }
+
+    #[test]
+    fn command_line_overrides_config() {
+        let matches = Opt::clap().get_matches_from(vec!["sd3norm", "-f", "json", "--no-clobber", "study.xlsx"]);
+        let mut opts = Opt::from_clap(&matches).normalize;
+        let config = Config {
+            input: Some(vec![PathBuf::from("data")]),
+            format: Some("xlsx".to_string()),
+            on_invalid: Some("flag".to_string()),
+            force: Some(true),
+            ..Config::default()
+        };
+        apply_config(&mut opts, &matches, &config).unwrap();
+
+        assert_eq!(opts.inputs.input, vec![PathBuf::from("study.xlsx")]);
+        assert_eq!(opts.format, Format::Json);
+        assert_eq!(opts.inputs.on_invalid, InvalidPolicy::Flag);
+        assert!(opts.no_clobber && !opts.force);
+
+        let matches = Opt::clap().get_matches_from(vec!["sd3norm", "--no-strict", "--no-fail-fast", "study.xlsx"]);
+        let mut opts = Opt::from_clap(&matches).normalize;
+        let config = Config { strict: Some(true), fail_fast: Some(true), provenance: Some(true), ..Config::default() };
+        apply_config(&mut opts, &matches, &config).unwrap();
+
+        assert!(!opts.strict && !opts.fail_fast);
+        assert!(opts.provenance);
+    }
+
+    #[test]
+    fn flatten_can_take_its_out_dir_from_config() {
+        let matches = Opt::clap().get_matches_from(vec!["sd3norm", "--flatten", "study.xlsx"]);
+        let mut opts = Opt::from_clap(&matches);
+        assert!(check_requirements(&opts).is_err());
+
+        let config = Config { out_dir: Some(PathBuf::from("out")), ..Config::default() };
+        apply_config(&mut opts.normalize, &matches, &config).unwrap();
+        assert!(check_requirements(&opts).is_ok());
+    }
+
+    #[test]
+    fn stdin_needs_an_output_without_a_file_name() {
+        let check = |args: &[&str]| check_requirements(&Opt::from_clap(&Opt::clap().get_matches_from(args)));
+        assert!(check(&["sd3norm", "-"]).is_err());
+        assert!(check(&["sd3norm", "normalize", "a.csv", "-"]).is_err());
+        assert!(check(&["sd3norm", "--stdout", "-"]).is_ok());
+        assert!(check(&["sd3norm", "--merge", "all.csv", "-"]).is_ok());
+    }
+
+    #[test]
+    fn watch_only_writes_outputs_for_each_workbook() {
+        let check = |args: &[&str]| check_requirements(&Opt::from_clap(&Opt::clap().get_matches_from(args)));
+        assert!(check(&["sd3norm", "--merge", "all.csv", "watch", "drop"]).is_err());
+        assert!(check(&["sd3norm", "--stdout", "watch", "drop"]).is_err());
+        assert!(check(&["sd3norm", "-d", "out", "watch", "drop"]).is_ok());
+    }
+
+    #[test]
+    fn options_are_recorded_by_their_config_names() {
+        let matches = Opt::clap().get_matches_from(vec!["sd3norm", "-f", "json", "--provenance", "--dry-run", "a.csv"]);
+        let values = option_values(&Opt::from_clap(&matches).normalize, 2).unwrap();
+
+        assert_eq!(values["input"], "a.csv");
+        assert_eq!(values["input-format"], "auto");
+        assert_eq!(values["format"], "json");
+        assert_eq!(values["on-invalid"], "reject");
+        assert_eq!(values["on-collision"], "error");
+        assert_eq!(values["out-dir"], "");
+        assert_eq!(values["provenance"], "true");
+        assert_eq!(values["dry-run"], "true");
+        assert_eq!(values["json-summary"], "false");
+        assert_eq!(values["verbose"], "2");
+    }
+
+    #[test]
+    fn normalize_is_the_default_subcommand() {
+        let parse = |args: &[&str]| Opt::from_clap(&Opt::clap().get_matches_from(args));
+        let legacy = parse(&["sd3norm", "-v", "-f", "json", "study.xlsx"]);
+        let subcommand = parse(&["sd3norm", "normalize", "-f", "json", "study.xlsx", "-v"]);
+        match subcommand.cmd {
+            Some(Command::Normalize(ref normalize)) => assert_eq!(*normalize, legacy.normalize),
+            ref other => panic!("expected normalize, parsed {:?}", other),
+        }
+        assert_eq!(legacy.verbose, subcommand.verbose);
+        assert!(check_order(&legacy).is_ok() && check_order(&subcommand).is_ok());
+
+        assert!(check_order(&parse(&["sd3norm", "-d", "out", "watch", "drop"])).is_ok());
+        assert!(check_order(&parse(&["sd3norm", "-f", "json", "validate", "study.xlsx"])).is_err());
+        assert!(check_order(&parse(&["sd3norm", "study.xlsx", "summarize"])).is_err());
+    }
+
+    #[test]
+    fn incompatible_conversions_are_usage_errors() {
+        let convert = |from, to| run_units(&UnitsCommand::Convert { value: 1.0, from, to }).unwrap();
+        assert_eq!(convert(SIUnit::ml, SIUnit::ul), exit::OK);
+        assert_eq!(convert(SIUnit::ml, SIUnit::ng), exit::USAGE);
+    }
+
+    #[test]
+    fn conversions_are_rounded() {
+        let converted = si::convert((150.0, SIUnit::ng_ml), SIUnit::pg_ml).unwrap();
+        assert_eq!(significant(converted), 150_000.0);
+        assert_eq!(significant(0.1 + 0.2), 0.3);
+        assert_eq!(significant(-2.5e-9), -2.5e-9);
+    }
+}
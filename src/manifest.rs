@@ -0,0 +1,130 @@
+use failure::{Error, ResultExt};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// The file name of the manifest that `--incremental` runs keep in the output directory
+pub const FILE_NAME: &str = ".sd3norm-manifest.json";
+
+/// The version of sd3norm, and the options that change what it writes.
+/// Outputs made with different settings are rebuilt, even if their inputs are unchanged
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Settings {
+    pub version: String,
+    pub options: BTreeMap<String, String>,
+}
+
+/// An input that was normalized by an earlier run
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Entry {
+    sha256: String,
+    outputs: Vec<PathBuf>,
+}
+
+/// What earlier `--incremental` runs normalized, so that unchanged inputs can be skipped
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Manifest {
+    settings: Settings,
+    /// Keyed by the absolute path of each input
+    inputs: BTreeMap<PathBuf, Entry>,
+    /// The inputs of a manifest made with other settings, whose outputs may still be replaced
+    #[serde(skip)]
+    outdated: BTreeMap<PathBuf, Entry>,
+}
+
+impl Manifest {
+    /// Load the manifest at `path`, forgetting every input in it if it was made with other `settings`
+    pub fn load(path: &Path, settings: Settings) -> Result<Manifest, Error> {
+        let mut manifest = Manifest { settings, inputs: BTreeMap::new(), outdated: BTreeMap::new() };
+        if !path.exists() {
+            return Ok(manifest);
+        }
+        let file = File::open(path).context(format!("opening manifest <{}>", path.display()))?;
+        let earlier: Manifest = ::serde_json::from_reader(file)
+            .context(format!("reading manifest <{}>", path.display()))?;
+        if earlier.settings == manifest.settings {
+            manifest.inputs = earlier.inputs;
+        } else {
+            info!("Rebuilding every output, since the settings in <{}> have changed", path.display());
+            manifest.outdated = earlier.inputs;
+        }
+        Ok(manifest)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), Error> {
+        let file = File::create(path).context(format!("creating manifest <{}>", path.display()))?;
+        ::serde_json::to_writer_pretty(file, self).context(format!("writing manifest <{}>", path.display()))?;
+        Ok(())
+    }
+
+    /// Check if an input with this hash was already normalized, and its outputs are all still there
+    pub fn is_current(&self, input: &Path, sha256: &str) -> bool {
+        self.inputs.get(&key(input)).is_some_and(|e| e.sha256 == sha256 && e.outputs.iter().all(|o| o.exists()))
+    }
+
+    /// The outputs written for an input by an earlier run, which may be replaced when it is rebuilt
+    pub fn outputs(&self, input: &Path) -> &[PathBuf] {
+        let key = key(input);
+        self.inputs.get(&key).or_else(|| self.outdated.get(&key)).map_or(&[], |e| &e.outputs)
+    }
+
+    pub fn record(&mut self, input: &Path, sha256: String, outputs: &[PathBuf]) {
+        let outputs = outputs.iter().map(|o| o.canonicalize().unwrap_or_else(|_| o.clone())).collect();
+        self.inputs.insert(key(input), Entry { sha256, outputs });
+    }
+
+    /// Forget an input, like one that failed, so the next run tries it again
+    pub fn forget(&mut self, input: &Path) {
+        self.inputs.remove(&key(input));
+    }
+}
+
+fn key(input: &Path) -> PathBuf {
+    input.canonicalize().unwrap_or_else(|_| input.to_path_buf())
+}
+
+/// The SHA-256 hash of a file's contents, in hex
+pub fn sha256(path: &Path) -> Result<String, Error> {
+    let mut file = File::open(path).context(format!("opening <{}> to hash it", path.display()))?;
+    let mut hasher = Sha256::new();
+    io::copy(&mut file, &mut hasher).context(format!("hashing <{}>", path.display()))?;
+    Ok(hasher.finalize().iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use utils::TempDir;
+
+    #[test]
+    fn unchanged_inputs_are_current() {
+        let dir = TempDir::new("manifest");
+        let input = dir.join("input.csv");
+        let output = dir.join("output.csv");
+        let path = dir.join("manifest.json");
+        fs::write(&input, "abc").unwrap();
+        fs::write(&output, "").unwrap();
+        let settings = |format: &str| Settings {
+            version: "1".to_string(),
+            options: vec![("format".to_string(), format.to_string())].into_iter().collect(),
+        };
+
+        let hash = sha256(&input).unwrap();
+        assert_eq!(hash, "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+        let mut manifest = Manifest::load(&path, settings("csv")).unwrap();
+        manifest.record(&input, hash.clone(), ::std::slice::from_ref(&output));
+        manifest.save(&path).unwrap();
+
+        let same = Manifest::load(&path, settings("csv")).unwrap().is_current(&input, &hash);
+        let other_settings = Manifest::load(&path, settings("xlsx")).unwrap().is_current(&input, &hash);
+        let other_hash = Manifest::load(&path, settings("csv")).unwrap().is_current(&input, "0");
+        fs::remove_file(&output).unwrap();
+        let output_gone = Manifest::load(&path, settings("csv")).unwrap().is_current(&input, &hash);
+
+        assert!(same);
+        assert!(!other_settings && !other_hash && !output_gone);
+    }
+}
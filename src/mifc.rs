@@ -1,6 +1,7 @@
 use si::{SIUnit};
 
 #[derive(Debug, Serialize, Deserialize)]
+#[allow(clippy::upper_case_acronyms)]
 pub struct MIFC {
     #[serde(rename = "Chip ID")]
     pub id: String,
@@ -37,3 +38,78 @@ pub struct MIFC {
     #[serde(rename = "Cross Reference")]
     pub xref: Option<String>,
 }
+
+/// A single typed value from an MIFC row
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Value<'a> {
+    Text(&'a str),
+    Number(f64),
+    Empty,
+}
+
+impl<'a> From<&'a Option<String>> for Value<'a> {
+    fn from(s: &'a Option<String>) -> Self {
+        s.as_ref().map_or(Value::Empty, |s| Value::Text(s))
+    }
+}
+
+impl<'a> From<Option<f64>> for Value<'a> {
+    fn from(n: Option<f64>) -> Self {
+        n.map_or(Value::Empty, Value::Number)
+    }
+}
+
+impl MIFC {
+    /// The column headers of an MIFC file, in order
+    pub const HEADERS: [&'static str; 17] = [
+        "Chip ID", "Assay Plate ID", "Assay Well ID", "Method/Kit", "Target/Analyte", "Subtarget",
+        "Sample Location", "Day", "Hour", "Minute", "Value", "Value Unit", "Caution Flag", "Exclude",
+        "Notes", "Replicate", "Cross Reference",
+    ];
+
+    /// The typed values of this row, in the same order as `HEADERS`
+    pub fn values(&self) -> [Value<'_>; 17] {
+        [
+            Value::Text(&self.id),
+            Value::from(&self.assay_plate_id),
+            Value::from(&self.assay_well_id),
+            Value::Text(&self.method),
+            Value::Text(&self.target),
+            Value::from(&self.subtarget),
+            Value::Text(&self.sample_loc),
+            Value::Number(self.day),
+            Value::Number(self.hour),
+            Value::Number(self.min),
+            Value::from(self.value),
+            self.value_unit.map_or(Value::Empty, |u| Value::Text(u.as_str())),
+            Value::from(&self.flag),
+            Value::from(&self.exclude),
+            Value::from(&self.notes),
+            Value::from(self.replicate.map(widen)),
+            Value::from(&self.xref),
+        ]
+    }
+}
+
+/// Widen an `f32` to the `f64` with the same shortest decimal form, so that a Replicate of 0.1
+/// isn't written as 0.10000000149011612
+fn widen(n: f32) -> f64 {
+    n.to_string().parse().unwrap_or(f64::from(n))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use utils::mifc_row;
+
+    #[test]
+    fn replicates_keep_their_decimal_form() {
+        for &replicate in &[0.1f32, 1.0, 2.3, 1e-7, 16_777_217.0] {
+            let row = MIFC { replicate: Some(replicate), ..mifc_row("A1", None) };
+            match row.values()[15] {
+                Value::Number(n) => assert_eq!(n.to_string(), replicate.to_string()),
+                ref v => panic!("replicate {} was written as {:?}", replicate, v),
+            }
+        }
+    }
+}
@@ -0,0 +1,181 @@
+use failure::Error;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// The placeholders that can be used in a `--name-template`
+const PLACEHOLDERS: [&str; 6] = ["stem", "sheet", "sheet_index", "date", "append", "ext"];
+
+/// A pattern for output file names, like "{stem}_{sheet}_{date}.{ext}"
+#[derive(Debug, Clone, PartialEq)]
+pub struct NameTemplate(String);
+
+impl FromStr for NameTemplate {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.contains('/') || s.contains('\\') {
+            bail!("a name template can't contain a directory, use \"--out-dir\" instead");
+        }
+        let mut rest = s;
+        while let Some(start) = rest.find('{') {
+            let end = rest[start..].find('}')
+                .ok_or_else(|| format_err!("name template has an unclosed \"{{\""))?;
+            let name = &rest[start + 1..start + end];
+            if !PLACEHOLDERS.contains(&name) {
+                bail!("unknown placeholder {{{}}} in name template, expected one of: {{{}}}",
+                    name, PLACEHOLDERS.join("}, {"));
+            }
+            rest = &rest[start + end + 1..];
+        }
+        Ok(NameTemplate(s.to_string()))
+    }
+}
+
+impl fmt::Display for NameTemplate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// How output files are named
+#[derive(Debug, Clone)]
+pub struct Naming {
+    /// Name outputs with this template, instead of "<stem>-<sheet>-<append>.<ext>"
+    pub template: Option<NameTemplate>,
+    pub append: String,
+    /// Today's date, for the {date} placeholder
+    pub date: String,
+}
+
+impl Naming {
+    pub fn new(template: Option<NameTemplate>, append: &str) -> Self {
+        Naming {
+            template,
+            append: append.to_string(),
+            date: ::chrono::Local::now().format("%Y-%m-%d").to_string(),
+        }
+    }
+
+    /// The output path for `output_base`, which has the directory, stem and extension of the output.
+    /// Outputs with a file for each sheet also pass the sheet's name and 1-based index
+    pub fn path(&self, output_base: &Path, sheet: Option<(&str, usize)>) -> PathBuf {
+        let stem = output_base.file_stem().map_or("".into(), |s| s.to_string_lossy());
+        let ext = output_base.extension().map_or("".into(), |e| e.to_string_lossy());
+
+        let name = match self.template {
+            Some(NameTemplate(ref template)) => render(template, |p| match p {
+                "stem" => sanitize(&stem, true),
+                "sheet" => sheet.map_or(String::new(), |(s, _)| sanitize(s, true)),
+                "sheet_index" => sheet.map_or(String::new(), |(_, i)| i.to_string()),
+                "date" => self.date.clone(),
+                "append" => sanitize(&self.append, true),
+                _ => ext.to_string(),
+            }),
+            None => match sheet {
+                Some((s, _)) => format!("{}-{}-{}.{}", stem, sanitize(s, false), self.append, ext),
+                None => format!("{}-{}.{}", stem, self.append, ext),
+            },
+        };
+        output_base.with_file_name(name)
+    }
+}
+
+/// Replace characters that can't be in a file name, and optionally `spaces`, with an underscore
+fn sanitize(s: &str, spaces: bool) -> String {
+    s.trim()
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() || (spaces && c.is_whitespace()) => '_',
+            c => c,
+        })
+        .collect()
+}
+
+/// Fill in each placeholder of `template` with its `value`. The separators that a placeholder with no value
+/// (like {sheet} for an output that holds every sheet) would leave behind are removed from the text next to it,
+/// while the rest of the name is kept as it is
+fn render<F: Fn(&str) -> String>(template: &str, value: F) -> String {
+    let is_sep = |c: char| c == '_' || c == '-' || c == ' ';
+
+    /* The text around the placeholders, with one more piece of text than there are placeholders */
+    let (mut text, mut values) = (Vec::new(), Vec::new());
+    let mut rest = template;
+    while let Some((start, end)) = rest.find('{').and_then(|s| rest[s..].find('}').map(|e| (s, s + e))) {
+        text.push(rest[..start].to_string());
+        values.push(value(&rest[start + 1..end]));
+        rest = &rest[end + 1..];
+    }
+    text.push(rest.to_string());
+
+    for (i, v) in values.iter().enumerate() {
+        if !v.is_empty() {
+            continue;
+        }
+        if text[i].ends_with(is_sep) {
+            let before = text[i].trim_end_matches(is_sep).len();
+            text[i].truncate(before);
+        } else if text[i + 1].starts_with(is_sep) {
+            text[i + 1] = text[i + 1].trim_start_matches(is_sep).to_string();
+        }
+    }
+
+    let mut name = text[0].clone();
+    for (v, t) in values.iter().zip(&text[1..]) {
+        name.push_str(v);
+        name.push_str(t);
+    }
+    name
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn naming(template: &str) -> Naming {
+        Naming {
+            template: Some(template.parse().unwrap()),
+            append: "normalized".to_string(),
+            date: "2024-03-01".to_string(),
+        }
+    }
+
+    #[test]
+    fn template_placeholders() {
+        let base = Path::new("out/study 1.csv");
+        let n = naming("{stem}_{sheet}_{date}.{ext}");
+        assert_eq!(n.path(base, Some(("Day 1/2", 2))), Path::new("out/study_1_Day_1_2_2024-03-01.csv"));
+        assert_eq!(n.path(base, None), Path::new("out/study_1_2024-03-01.csv"));
+        let n = naming("{sheet_index}-{stem}-{append}.{ext}");
+        assert_eq!(n.path(base, Some(("x", 3))), Path::new("out/3-study_1-normalized.csv"));
+        assert_eq!(n.path(base, None), Path::new("out/study_1-normalized.csv"));
+    }
+
+    #[test]
+    fn only_separators_next_to_empty_placeholders_are_removed() {
+        let base = Path::new("out/study--final.csv");
+        let n = naming("{stem}__{sheet}.{ext}");
+        assert_eq!(n.path(base, Some(("a", 1))), Path::new("out/study--final__a.csv"));
+        assert_eq!(n.path(base, None), Path::new("out/study--final.csv"));
+        let n = naming("{sheet}-{stem} - {append}.{ext}");
+        assert_eq!(n.path(base, None), Path::new("out/study--final - normalized.csv"));
+        assert_eq!(n.path(Path::new("out/study_.csv"), Some(("a_", 1))), Path::new("out/a_-study_ - normalized.csv"));
+    }
+
+    #[test]
+    fn legacy_names() {
+        let n = Naming { template: None, append: "normalized".to_string(), date: String::new() };
+        assert_eq!(n.path(Path::new("a/b.csv"), Some(("Sheet:1", 1))), Path::new("a/b-Sheet_1-normalized.csv"));
+        assert_eq!(n.path(Path::new("a/b.csv"), Some(("Other Sheet", 2))), Path::new("a/b-Other Sheet-normalized.csv"));
+        assert_eq!(n.path(Path::new("a/b.xlsx"), None), Path::new("a/b-normalized.xlsx"));
+    }
+
+    #[test]
+    fn bad_templates() {
+        assert!("{stem}.{ext}".parse::<NameTemplate>().is_ok());
+        assert!("{stem}_{shet}.{ext}".parse::<NameTemplate>().is_err());
+        assert!("{stem.{ext}".parse::<NameTemplate>().is_err());
+        assert!("out/{stem}.{ext}".parse::<NameTemplate>().is_err());
+    }
+}
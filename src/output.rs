@@ -0,0 +1,794 @@
+use calamine::DataType;
+use failure::{Error, ResultExt};
+use rust_xlsxwriter::{Workbook, Worksheet};
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use mifc::{MIFC, Value};
+use naming::Naming;
+use sd3::{SD3Error, RowContext};
+use workbook::Sheet;
+
+/// The file format that normalized rows are written in
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Format {
+    /// One CSV file for each input sheet
+    Csv,
+    /// One excel workbook for each input workbook, with a worksheet for each input sheet
+    Xlsx,
+    /// One JSON array of rows for each input workbook
+    Json,
+    /// One file of newline-delimited JSON rows for each input workbook
+    Ndjson,
+    /// One Apache Parquet file for each input workbook
+    Parquet,
+    /// One Arrow IPC file for each input workbook
+    Arrow,
+}
+
+impl Format {
+    pub fn extension(self) -> &'static str {
+        match self {
+            Format::Csv => "csv",
+            Format::Xlsx => "xlsx",
+            Format::Json => "json",
+            Format::Ndjson => "ndjson",
+            Format::Parquet => "parquet",
+            Format::Arrow => "arrow",
+        }
+    }
+}
+
+impl FromStr for Format {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(Format::Csv),
+            "xlsx" => Ok(Format::Xlsx),
+            "json" => Ok(Format::Json),
+            "ndjson" => Ok(Format::Ndjson),
+            "parquet" => Ok(Format::Parquet),
+            "arrow" => Ok(Format::Arrow),
+            _ => Err(format_err!("unknown output format <{}>", s)),
+        }
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.extension())
+    }
+}
+
+/// Where an output that holds every sheet of a workbook, or of a whole run, is written
+#[derive(Debug, Clone, PartialEq)]
+pub enum Destination {
+    File(PathBuf),
+    Stdout,
+}
+
+impl Destination {
+    /// Create (or replace) the output
+    pub fn create(&self) -> Result<Output, Error> {
+        info!("Output file: {:?}", self.path());
+        match self {
+            Destination::File(path) => Ok(Output::File(PartFile::create(path)?)),
+            Destination::Stdout => Ok(Output::Stdout(io::stdout())),
+        }
+    }
+
+    /// The path of the output file, or "-" for stdout
+    pub fn path(&self) -> PathBuf {
+        match self {
+            Destination::File(path) => path.clone(),
+            Destination::Stdout => PathBuf::from("-"),
+        }
+    }
+}
+
+/// An output that is being written to its `Destination`
+#[derive(Debug)]
+pub enum Output {
+    File(PartFile),
+    Stdout(io::Stdout),
+}
+
+impl Output {
+    /// Finish writing the output, which must be done to keep a file
+    pub fn finish(self) -> Result<(), Error> {
+        match self {
+            Output::File(file) => file.finish(),
+            Output::Stdout(mut out) => Ok(out.flush()?),
+        }
+    }
+}
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Output::File(file) => file.write(buf),
+            Output::Stdout(out) => out.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Output::File(file) => file.flush(),
+            Output::Stdout(out) => out.flush(),
+        }
+    }
+}
+
+/// An output file that is written under a ".part" name until it is finished, and removed if it never is.
+/// That way a workbook that fails part way through leaves no half-written output, and any file it was
+/// replacing is kept
+#[derive(Debug)]
+pub struct PartFile {
+    path: PathBuf,
+    part: PathBuf,
+    /// The open ".part" file, which is closed when it's finished
+    file: Option<File>,
+    kept: bool,
+}
+
+impl PartFile {
+    /// Create (or truncate) the ".part" file of an output, once the run's `OutputGuard` has allowed it to be replaced
+    fn create(path: &Path) -> Result<PartFile, Error> {
+        let mut part = path.as_os_str().to_os_string();
+        part.push(".part");
+        let part = PathBuf::from(part);
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&part)
+            .context(format!("creating output <{}>", path.display()))?;
+        Ok(PartFile { path: path.to_path_buf(), part, file: Some(file), kept: false })
+    }
+
+    /// Give the written file its own name
+    fn finish(mut self) -> Result<(), Error> {
+        if let Some(mut file) = self.file.take() {
+            file.flush()?;
+        }
+        fs::rename(&self.part, &self.path).context(format!("writing output <{}>", self.path.display()))?;
+        self.kept = true;
+        Ok(())
+    }
+}
+
+impl Write for PartFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.as_mut().expect("the output was finished").write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.as_mut().expect("the output was finished").flush()
+    }
+}
+
+impl Drop for PartFile {
+    fn drop(&mut self) {
+        if !self.kept {
+            let _ = fs::remove_file(&self.part);
+        }
+    }
+}
+
+/// Destination for normalized rows, either from one input workbook or from every workbook in a run
+pub trait Writer {
+    /// Start writing the rows of workbook `wb_path`
+    fn start_workbook(&mut self, _wb_path: &Path) -> Result<(), Error> {
+        Ok(())
+    }
+    /// Start writing the rows of the next input sheet
+    fn start_sheet(&mut self, sheet: &Sheet) -> Result<(), Error>;
+    /// Write a normalized row of the current sheet, found at `ctx` in the input
+    fn write(&mut self, ctx: &RowContext, row: &MIFC) -> Result<(), Error>;
+    /// Keep a record of a row in the current sheet that could not be normalized
+    fn reject(&mut self, _cells: &[DataType], _error: &SD3Error) -> Result<(), Error> {
+        Ok(())
+    }
+    /// The files that a dry run would write, and how many rows each would get
+    fn planned(&self) -> Vec<(PathBuf, usize)> {
+        Vec::new()
+    }
+    /// Finish writing, returning the paths of every file that was written
+    fn finish(self: Box<Self>) -> Result<Vec<PathBuf>, Error>;
+}
+
+/// The output files of a workbook with `sheets`, in the order they are written.
+/// `output_base` is the directory, stem and extension of the output file, which `naming` makes a name from
+pub fn paths(format: Format, output_base: &Path, naming: &Naming, sheets: &[Sheet]) -> Vec<PathBuf> {
+    if format != Format::Csv {
+        return vec![naming.path(output_base, None)];
+    }
+    /* If there is only one sheet, don't add the sheet name to the default output file name */
+    let add_sheet = naming.template.is_some() || sheets.len() > 1;
+    sheets.iter()
+        .enumerate()
+        .map(|(i, sheet)| naming.path(output_base, if add_sheet { Some((sheet.name.as_str(), i + 1)) } else { None }))
+        .collect()
+}
+
+/// Create a writer for the normalized rows of a workbook, into the output files from `paths`
+pub fn writer(format: Format, paths: Vec<PathBuf>) -> Result<Box<dyn Writer>, Error> {
+    if format == Format::Csv {
+        return Ok(Box::new(CsvWriter {
+            paths: paths.into_iter(),
+            current: None,
+            done: Vec::new(),
+        }));
+    }
+    let path = paths.into_iter().next().ok_or_else(|| format_err!("no output file was given"))?;
+    file_writer(format, Destination::File(path), false)
+}
+
+/// Create a writer for `--dry-run`, which writes nothing, but prints each of the output files from `paths`
+/// that would be written with the number of rows it would get. With `per_sheet`, there is a file for each sheet
+pub fn dry_run_writer(paths: Vec<PathBuf>, per_sheet: bool) -> Box<dyn Writer> {
+    Box::new(DryRunWriter { paths, per_sheet, rows: Vec::new() })
+}
+
+/// Create a writer that writes nothing, for counting what normalizing would do
+pub fn discard_writer() -> Box<dyn Writer> {
+    Box::new(DiscardWriter)
+}
+
+/// Create a writer that combines the normalized rows of every workbook in a run into the one output `dest`,
+/// with "Source File" and "Source Sheet" columns to tell where each row came from
+pub fn merged_writer(format: Format, dest: Destination) -> Result<Box<dyn Writer>, Error> {
+    if format == Format::Csv {
+        let mut out = csv::Writer::from_writer(dest.create()?);
+        out.write_record(MIFC::HEADERS.iter().chain(&["Source File", "Source Sheet"]))?;
+        return Ok(Box::new(MergedCsvWriter { dest, out, workbook: String::new() }));
+    }
+    file_writer(format, dest, true)
+}
+
+/// Create a writer for a format that puts every sheet into a single file
+fn file_writer(format: Format, dest: Destination, merged: bool) -> Result<Box<dyn Writer>, Error> {
+    let writer: Box<dyn Writer> = match format {
+        Format::Csv => unreachable!("csv files are written one sheet at a time"),
+        Format::Xlsx => Box::new(XlsxWriter {
+            dest,
+            workbook: Workbook::new(),
+            merged,
+            source_file: String::new(),
+            current: None,
+            rejected: None,
+            headers: Vec::new(),
+            sheet_names: Vec::new(),
+        }),
+        Format::Json | Format::Ndjson => {
+            let out = BufWriter::new(dest.create()?);
+            Box::new(JsonWriter {
+                workbook: PathBuf::new(),
+                dest,
+                out,
+                lines: format == Format::Ndjson,
+                count: 0,
+            })
+        },
+        Format::Parquet | Format::Arrow => columnar_writer(format, dest)?,
+    };
+    Ok(writer)
+}
+
+struct CsvWriter {
+    /// The output file of each sheet that is still to be started
+    paths: ::std::vec::IntoIter<PathBuf>,
+    current: Option<csv::Writer<PartFile>>,
+    /// The output files of the sheets that have been written, which are kept once the whole workbook is
+    done: Vec<PartFile>,
+}
+
+impl CsvWriter {
+    fn finish_current(&mut self) -> Result<(), Error> {
+        if let Some(wtr) = self.current.take() {
+            self.done.push(wtr.into_inner().map_err(|e| e.into_error())?);
+        }
+        Ok(())
+    }
+}
+
+impl Writer for CsvWriter {
+    fn start_sheet(&mut self, sheet: &Sheet) -> Result<(), Error> {
+        self.finish_current()?;
+
+        let output = self.paths.next()
+            .ok_or_else(|| format_err!("no output file for sheet <{}>", sheet.name))?;
+        info!("Output file: {:?}", &output);
+
+        self.current = Some(csv::Writer::from_writer(PartFile::create(&output)?));
+        Ok(())
+    }
+
+    fn write(&mut self, _ctx: &RowContext, row: &MIFC) -> Result<(), Error> {
+        let wtr = self.current.as_mut().ok_or_else(|| format_err!("no output sheet was started"))?;
+        wtr.serialize(row)?;
+        Ok(())
+    }
+
+    fn finish(mut self: Box<Self>) -> Result<Vec<PathBuf>, Error> {
+        self.finish_current()?;
+        let mut written = Vec::new();
+        for file in self.done.drain(..) {
+            written.push(file.path.clone());
+            file.finish()?;
+        }
+        Ok(written)
+    }
+}
+
+struct DiscardWriter;
+
+impl Writer for DiscardWriter {
+    fn start_sheet(&mut self, _sheet: &Sheet) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn write(&mut self, _ctx: &RowContext, _row: &MIFC) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn finish(self: Box<Self>) -> Result<Vec<PathBuf>, Error> {
+        Ok(Vec::new())
+    }
+}
+
+struct DryRunWriter {
+    paths: Vec<PathBuf>,
+    per_sheet: bool,
+    /// The number of rows written to each sheet
+    rows: Vec<usize>,
+}
+
+impl Writer for DryRunWriter {
+    fn start_sheet(&mut self, _sheet: &Sheet) -> Result<(), Error> {
+        self.rows.push(0);
+        Ok(())
+    }
+
+    fn write(&mut self, _ctx: &RowContext, _row: &MIFC) -> Result<(), Error> {
+        *self.rows.last_mut().ok_or_else(|| format_err!("no output sheet was started"))? += 1;
+        Ok(())
+    }
+
+    fn planned(&self) -> Vec<(PathBuf, usize)> {
+        if self.per_sheet {
+            self.paths.iter().cloned().zip(self.rows.iter().cloned()).collect()
+        } else {
+            self.paths.first().map(|path| (path.clone(), self.rows.iter().sum())).into_iter().collect()
+        }
+    }
+
+    fn finish(self: Box<Self>) -> Result<Vec<PathBuf>, Error> {
+        Ok(Vec::new())
+    }
+}
+
+struct MergedCsvWriter {
+    dest: Destination,
+    out: csv::Writer<Output>,
+    workbook: String,
+}
+
+impl Writer for MergedCsvWriter {
+    fn start_workbook(&mut self, wb_path: &Path) -> Result<(), Error> {
+        self.workbook = wb_path.to_string_lossy().into_owned();
+        Ok(())
+    }
+
+    fn start_sheet(&mut self, _sheet: &Sheet) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn write(&mut self, ctx: &RowContext, row: &MIFC) -> Result<(), Error> {
+        /* Debug formatting keeps the ".0" on whole numbers, like the csv serializer does */
+        let values = row.values();
+        let fields = values.iter()
+            .map(|v| match *v {
+                Value::Text(s) => s.to_string(),
+                Value::Number(n) => format!("{:?}", n),
+                Value::Empty => String::new(),
+            })
+            .chain(vec![self.workbook.clone(), ctx.sheet.clone()]);
+        self.out.write_record(fields)?;
+        Ok(())
+    }
+
+    fn finish(self: Box<Self>) -> Result<Vec<PathBuf>, Error> {
+        self.out.into_inner().map_err(|e| Error::from(e.into_error())).and_then(Output::finish)
+            .context(format!("writing output <{}>", self.dest.path().display()))?;
+        Ok(vec![self.dest.path()])
+    }
+}
+
+/// Name of the extra worksheet holding rows that could not be normalized
+const REJECTED_SHEET: &str = "Rejected Rows";
+
+/// Name of the worksheet holding every normalized row, when combining workbooks
+const MERGED_SHEET: &str = "Normalized";
+
+struct XlsxWriter {
+    dest: Destination,
+    workbook: Workbook,
+    /// Write the rows of every sheet into one worksheet, with the file and sheet each came from
+    merged: bool,
+    source_file: String,
+    /// The worksheet being written, along with its next row number
+    current: Option<(Worksheet, u32)>,
+    /// The rejected rows worksheet, its next row number, and the last headers written to it
+    rejected: Option<(Worksheet, u32, Vec<String>)>,
+    /// Headers of the input sheet being written
+    headers: Vec<String>,
+    sheet_names: Vec<String>,
+}
+
+impl XlsxWriter {
+    fn push_current(&mut self) {
+        if let Some((mut ws, _)) = self.current.take() {
+            ws.autofit();
+            self.workbook.push_worksheet(ws);
+        }
+    }
+}
+
+impl Writer for XlsxWriter {
+    fn start_workbook(&mut self, wb_path: &Path) -> Result<(), Error> {
+        self.source_file = wb_path.to_string_lossy().into_owned();
+        Ok(())
+    }
+
+    fn start_sheet(&mut self, sheet: &Sheet) -> Result<(), Error> {
+        self.headers = sheet.headers();
+        if self.merged && self.current.is_some() {
+            return Ok(());
+        }
+        self.push_current();
+
+        let mut ws = Worksheet::new();
+        let (name, source_headers) = if self.merged {
+            (MERGED_SHEET, &["Source File", "Source Sheet"][..])
+        } else {
+            (sheet.name.as_str(), &[][..])
+        };
+        ws.set_name(worksheet_name(name, &mut self.sheet_names))?;
+        for (col, header) in MIFC::HEADERS.iter().chain(source_headers).enumerate() {
+            ws.write_string(0, col as u16, *header)?;
+        }
+        ws.set_freeze_panes(1, 0)?;
+
+        self.current = Some((ws, 1));
+        Ok(())
+    }
+
+    fn write(&mut self, ctx: &RowContext, row: &MIFC) -> Result<(), Error> {
+        let (ws, next) = self.current.as_mut().ok_or_else(|| format_err!("no output sheet was started"))?;
+        for (col, value) in row.values().iter().enumerate() {
+            let col = col as u16;
+            match *value {
+                Value::Text(s) => { ws.write_string(*next, col, s)?; },
+                Value::Number(n) => { ws.write_number(*next, col, n)?; },
+                Value::Empty => (),
+            }
+        }
+        if self.merged {
+            let col = MIFC::HEADERS.len() as u16;
+            ws.write_string(*next, col, &self.source_file)?;
+            ws.write_string(*next, col + 1, &ctx.sheet)?;
+        }
+        *next += 1;
+        Ok(())
+    }
+
+    fn reject(&mut self, cells: &[DataType], error: &SD3Error) -> Result<(), Error> {
+        if self.rejected.is_none() {
+            let mut ws = Worksheet::new();
+            ws.set_name(worksheet_name(REJECTED_SHEET, &mut self.sheet_names))?;
+            ws.set_freeze_panes(1, 0)?;
+            self.rejected = Some((ws, 0, Vec::new()));
+        }
+        let (ws, next, last_headers) = self.rejected.as_mut().unwrap();
+        let ctx = error.context();
+        let prefix: &[&str] = if self.merged {
+            &["Source File", "Source Sheet", "Row", "Reason"]
+        } else {
+            &["Sheet", "Row", "Reason"]
+        };
+
+        /* Repeat the header row whenever the layout of the input sheet changes */
+        if *last_headers != self.headers {
+            let headers = prefix.iter()
+                .map(|h| h.to_string())
+                .chain(self.headers.iter().cloned());
+            for (col, header) in headers.enumerate() {
+                ws.write_string(*next, col as u16, header)?;
+            }
+            *last_headers = self.headers.clone();
+            *next += 1;
+        }
+
+        let mut col = 0;
+        if self.merged {
+            ws.write_string(*next, col, &self.source_file)?;
+            col += 1;
+        }
+        ws.write_string(*next, col, &ctx.sheet)?;
+        ws.write_number(*next, col + 1, ctx.row)?;
+        ws.write_string(*next, col + 2, error.to_string())?;
+        let first_cell = prefix.len() as u16;
+        for (col, cell) in cells.iter().enumerate() {
+            let col = col as u16 + first_cell;
+            match *cell {
+                DataType::Int(i) => { ws.write_number(*next, col, i as f64)?; },
+                DataType::Float(f) => { ws.write_number(*next, col, f)?; },
+                DataType::String(ref s) => { ws.write_string(*next, col, s)?; },
+                DataType::Bool(b) => { ws.write_boolean(*next, col, b)?; },
+                DataType::Error(ref e) => { ws.write_string(*next, col, e.to_string())?; },
+                DataType::Empty => (),
+            }
+        }
+        *next += 1;
+        Ok(())
+    }
+
+    fn finish(mut self: Box<Self>) -> Result<Vec<PathBuf>, Error> {
+        self.push_current();
+        if let Some((mut ws, _, _)) = self.rejected.take() {
+            ws.autofit();
+            self.workbook.push_worksheet(ws);
+        }
+        let path = self.dest.path();
+        let saved = self.workbook.save_to_buffer()
+            .map_err(Error::from)
+            .and_then(|buf| {
+                let mut out = self.dest.create()?;
+                out.write_all(&buf)?;
+                out.finish()
+            });
+        saved.context(format!("writing output <{}>", path.display()))?;
+        Ok(vec![path])
+    }
+}
+
+#[cfg(feature = "arrow")]
+fn columnar_writer(format: Format, dest: Destination) -> Result<Box<dyn Writer>, Error> {
+    use columnar::{ColumnarWriter, Container};
+
+    let container = if format == Format::Parquet { Container::Parquet } else { Container::ArrowIpc };
+    Ok(Box::new(ColumnarWriter::new(dest, container)))
+}
+
+#[cfg(not(feature = "arrow"))]
+fn columnar_writer(format: Format, _dest: Destination) -> Result<Box<dyn Writer>, Error> {
+    bail!("sd3norm was built without the \"arrow\" feature, so it can't write {} files", format.extension())
+}
+
+/// Where in the input a normalized row came from
+#[derive(Serialize)]
+struct Source<'a> {
+    workbook: &'a Path,
+    sheet: &'a str,
+    row: u32,
+}
+
+/// A normalized row along with its source, as written to JSON outputs
+#[derive(Serialize)]
+struct JsonRow<'a> {
+    source: Source<'a>,
+    #[serde(flatten)]
+    row: &'a MIFC,
+}
+
+struct JsonWriter {
+    workbook: PathBuf,
+    dest: Destination,
+    out: BufWriter<Output>,
+    /// Write one row per line, instead of a single array
+    lines: bool,
+    count: usize,
+}
+
+impl Writer for JsonWriter {
+    fn start_workbook(&mut self, wb_path: &Path) -> Result<(), Error> {
+        self.workbook = wb_path.to_path_buf();
+        Ok(())
+    }
+
+    fn start_sheet(&mut self, _sheet: &Sheet) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn write(&mut self, ctx: &RowContext, row: &MIFC) -> Result<(), Error> {
+        let row = JsonRow {
+            source: Source { workbook: &self.workbook, sheet: &ctx.sheet, row: ctx.row },
+            row,
+        };
+        if !self.lines {
+            self.out.write_all(if self.count == 0 { b"[\n" } else { b",\n" })?;
+        }
+        serde_json::to_writer(&mut self.out, &row)?;
+        if self.lines {
+            self.out.write_all(b"\n")?;
+        }
+        self.count += 1;
+        Ok(())
+    }
+
+    fn finish(mut self: Box<Self>) -> Result<Vec<PathBuf>, Error> {
+        if !self.lines {
+            self.out.write_all(if self.count == 0 { b"[]\n" } else { b"\n]\n" })?;
+        }
+        self.out.into_inner().map_err(|e| Error::from(e.into_error())).and_then(Output::finish)
+            .context(format!("writing output <{}>", self.dest.path().display()))?;
+        Ok(vec![self.dest.path()])
+    }
+}
+
+/// Make a valid and unique excel worksheet name from an input sheet name
+fn worksheet_name(name: &str, used: &mut Vec<String>) -> String {
+    const MAX_LEN: usize = 31;
+
+    let clean: String = name.chars()
+        .map(|c| if "[]:*?/\\".contains(c) { '_' } else { c })
+        .take(MAX_LEN)
+        .collect();
+    let mut unique = clean.clone();
+    let mut n = 1;
+    while used.iter().any(|u| u.eq_ignore_ascii_case(&unique)) {
+        n += 1;
+        let suffix = format!(" ({})", n);
+        let stem: String = clean.chars().take(MAX_LEN - suffix.len()).collect();
+        unique = stem + &suffix;
+    }
+    used.push(unique.clone());
+    unique
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use utils::{TempDir, mifc_row};
+
+    #[test]
+    fn unique_worksheet_names() {
+        let mut used = Vec::new();
+        assert_eq!(worksheet_name("Day 1/2", &mut used), "Day 1_2");
+        assert_eq!(worksheet_name("day 1_2", &mut used), "day 1_2 (2)");
+        assert_eq!(worksheet_name(&"x".repeat(40), &mut used), "x".repeat(31));
+        assert_eq!(worksheet_name(&"x".repeat(40), &mut used), "x".repeat(27) + " (2)");
+    }
+
+    #[test]
+    fn unfinished_outputs_are_removed() {
+        let dir = TempDir::new("unfinished");
+        let paths = vec![dir.join("study-A.csv"), dir.join("study-B.csv")];
+        let sheet = ::workbook::read_delimited("A", b"Chip ID\n", b',').unwrap();
+        let files = || {
+            let mut names: Vec<_> = fs::read_dir(dir.path()).unwrap().map(|e| e.unwrap().file_name()).collect();
+            names.sort();
+            names
+        };
+
+        let mut failed = writer(Format::Csv, paths.clone()).unwrap();
+        failed.start_sheet(&sheet).unwrap();
+        failed.start_sheet(&sheet).unwrap();
+        let while_writing = files();
+        drop(failed);
+        let after_failing = files();
+
+        let mut finished = writer(Format::Csv, paths.clone()).unwrap();
+        finished.start_sheet(&sheet).unwrap();
+        finished.write(&RowContext { sheet: "A".to_string(), row: 2 }, &mifc_row("A1", Some(1.0))).unwrap();
+        finished.start_sheet(&sheet).unwrap();
+        let written = finished.finish().unwrap();
+        let after_finishing = files();
+        let rows = fs::read_to_string(&paths[0]).unwrap().lines().count();
+
+        assert_eq!(while_writing, ["study-A.csv.part", "study-B.csv.part"]);
+        assert!(after_failing.is_empty());
+        assert_eq!(written, paths);
+        assert_eq!(after_finishing, ["study-A.csv", "study-B.csv"]);
+        assert_eq!(rows, 2);
+    }
+
+    #[test]
+    fn xlsx_outputs_have_a_worksheet_for_each_sheet_and_rejected_rows() {
+        use calamine::{open_workbook, Reader, Xlsx};
+
+        let dir = TempDir::new("xlsx");
+        let path = dir.join("study.xlsx");
+        let mut out = writer(Format::Xlsx, vec![path.clone()]).unwrap();
+        out.start_workbook(Path::new("study.xlsx")).unwrap();
+        out.start_sheet(&::workbook::read_delimited("Day 1", b"Chip ID,Day\n", b',').unwrap()).unwrap();
+        out.write(&RowContext { sheet: "Day 1".to_string(), row: 2 }, &mifc_row("A1", Some(10.0))).unwrap();
+        let missing = SD3Error::NoValue(RowContext { sheet: "Day 1".to_string(), row: 3 });
+        out.reject(&[DataType::String("A2".to_string()), DataType::Float(1.0)], &missing).unwrap();
+        out.start_sheet(&::workbook::read_delimited("Day 2", b"Chip ID\n", b',').unwrap()).unwrap();
+        assert_eq!(out.finish().unwrap(), vec![path.clone()]);
+
+        let mut xlsx: Xlsx<_> = open_workbook(&path).unwrap();
+        assert_eq!(xlsx.sheet_names(), ["Day 1", "Day 2", REJECTED_SHEET]);
+        let rows = |xlsx: &mut Xlsx<_>, name| -> Vec<Vec<DataType>> {
+            xlsx.worksheet_range(name).unwrap().unwrap().rows().map(|r| r.to_vec()).collect()
+        };
+        let text = |s: &str| DataType::String(s.to_string());
+
+        let day1 = rows(&mut xlsx, "Day 1");
+        assert_eq!(day1.len(), 2);
+        assert_eq!(day1[0], MIFC::HEADERS.iter().map(|h| text(h)).collect::<Vec<_>>());
+        assert_eq!(day1[1][..3], [text("A1"), DataType::Empty, DataType::Empty]);
+        assert_eq!(day1[1][7], DataType::Float(1.0));
+        assert_eq!(day1[1][10], DataType::Float(10.0));
+        assert_eq!(rows(&mut xlsx, "Day 2").len(), 1);
+
+        let rejected = rows(&mut xlsx, REJECTED_SHEET);
+        assert_eq!(rejected[0], [text("Sheet"), text("Row"), text("Reason"), text("Chip ID"), text("Day")]);
+        assert_eq!(rejected[1], [
+            text("Day 1"), DataType::Float(3.0), text(&missing.to_string()), text("A2"), DataType::Float(1.0),
+        ]);
+    }
+
+    #[test]
+    fn json_rows_of_every_sheet_are_one_array() {
+        let dir = TempDir::new("json");
+        let sheet = |name| ::workbook::read_delimited(name, b"Chip ID\n", b',').unwrap();
+        let write = |format, rows: &[(&str, &str)]| {
+            let path = dir.join(format!("study.{}", format));
+            let mut out = writer(format, vec![path.clone()]).unwrap();
+            out.start_workbook(Path::new("study.xlsx")).unwrap();
+            for name in &["A", "B", "C"] {
+                out.start_sheet(&sheet(name)).unwrap();
+                for &(_, id) in rows.iter().filter(|r| r.0 == *name) {
+                    out.write(&RowContext { sheet: name.to_string(), row: 2 }, &mifc_row(id, None)).unwrap();
+                }
+            }
+            out.finish().unwrap();
+            fs::read_to_string(path).unwrap()
+        };
+
+        let json = write(Format::Json, &[("A", "A1"), ("C", "C1"), ("C", "C2")]);
+        let rows: ::serde_json::Value = ::serde_json::from_str(&json).unwrap();
+        let rows = rows.as_array().unwrap();
+        assert_eq!(rows.len(), 3);
+        assert_eq!(rows[1]["Chip ID"], "C1");
+        assert_eq!(rows[1]["source"]["workbook"], "study.xlsx");
+        assert_eq!(rows[1]["source"]["sheet"], "C");
+        assert_eq!(rows[1]["source"]["row"], 2);
+
+        assert_eq!(write(Format::Json, &[]), "[]\n");
+        let lines = write(Format::Ndjson, &[("A", "A1"), ("C", "C1")]);
+        assert_eq!(lines.lines().count(), 2);
+        assert!(lines.lines().all(|l| ::serde_json::from_str::<::serde_json::Value>(l).unwrap().is_object()));
+    }
+
+    #[test]
+    fn merged_rows_say_where_they_came_from() {
+        let dir = TempDir::new("merged");
+        let path = dir.join("all.csv");
+        let mut out = merged_writer(Format::Csv, Destination::File(path.clone())).unwrap();
+        for (workbook, sheet, id) in &[("a.xlsx", "Day 1", "A1"), ("sub/b.csv", "b", "B1")] {
+            out.start_workbook(Path::new(workbook)).unwrap();
+            out.start_sheet(&::workbook::read_delimited(sheet, b"Chip ID\n", b',').unwrap()).unwrap();
+            out.write(&RowContext { sheet: sheet.to_string(), row: 2 }, &mifc_row(id, Some(10.0))).unwrap();
+        }
+        assert_eq!(out.finish().unwrap(), vec![path.clone()]);
+
+        let mut csv = csv::Reader::from_path(&path).unwrap();
+        let headers: Vec<_> = csv.headers().unwrap().iter().map(String::from).collect();
+        let rows: Vec<Vec<_>> = csv.records().map(|r| r.unwrap().iter().map(String::from).collect()).collect();
+        let column = |name| headers.iter().position(|h| h == name).unwrap();
+        assert_eq!(headers.len(), MIFC::HEADERS.len() + 2);
+        assert_eq!(&headers[MIFC::HEADERS.len()..], ["Source File", "Source Sheet"]);
+        assert_eq!(rows.len(), 2);
+        assert_eq!(&rows[1][MIFC::HEADERS.len()..], ["sub/b.csv", "b"]);
+        assert_eq!(&rows[1][column("Chip ID")], "B1");
+        assert_eq!(&rows[0][column("Value")], "10.0");
+        assert_eq!(&rows[0][column("Subtarget")], "");
+    }
+}
@@ -0,0 +1,86 @@
+use failure::{Error, ResultExt};
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::path::{Path, PathBuf};
+use manifest::sha256;
+use report::{SheetRows, Summary};
+
+/// A file that was read or written, and its contents' SHA-256 hash (if it's a file that can be hashed)
+#[derive(Debug, Serialize)]
+pub struct Hashed {
+    pub path: PathBuf,
+    pub sha256: Option<String>,
+}
+
+/// A record of what a run read, how, and what it wrote, for audits
+#[derive(Debug, Serialize)]
+struct Provenance<'a> {
+    version: &'static str,
+    /// When the run finished, in RFC 3339 format
+    timestamp: String,
+    options: &'a BTreeMap<String, String>,
+    inputs: &'a [Hashed],
+    sheets: &'a [SheetRows],
+    outputs: Vec<Hashed>,
+    /// The factor that puts each unit that values were converted from (or through) into its base SI unit
+    si_factors: BTreeMap<&'static str, f64>,
+    summary: &'a Summary,
+}
+
+/// Write a provenance manifest for a run into `dir`, returning its path
+pub fn write(dir: &Path, options: &BTreeMap<String, String>, inputs: &[Hashed], summary: &Summary)
+    -> Result<PathBuf, Error>
+{
+    let now = ::chrono::Local::now();
+
+    let outputs = summary.outputs.iter()
+        .map(|o| Hashed { path: o.clone(), sha256: if o.is_file() { sha256(o).ok() } else { None } })
+        .collect();
+    let provenance = Provenance {
+        version: env!("CARGO_PKG_VERSION"),
+        timestamp: now.to_rfc3339(),
+        options,
+        inputs,
+        sheets: &summary.sheet_rows,
+        outputs,
+        si_factors: summary.units.iter().map(|u| (u.as_str(), u.si_factor())).collect(),
+        summary,
+    };
+
+    let (path, file) = create(dir, &now.format("%Y%m%dT%H%M%S").to_string())?;
+    ::serde_json::to_writer_pretty(file, &provenance)
+        .context(format!("writing provenance manifest <{}>", path.display()))?;
+    info!("Provenance manifest: {:?}", path);
+    Ok(path)
+}
+
+/// Create a new provenance manifest in `dir` for a run that finished at `time`. Runs that finish in the same
+/// second get a "-2", "-3", ... suffix, instead of replacing each other's manifests
+fn create(dir: &Path, time: &str) -> Result<(PathBuf, File), Error> {
+    for n in 1.. {
+        let suffix = if n == 1 { String::new() } else { format!("-{}", n) };
+        let path = dir.join(format!("sd3norm-provenance-{}{}.json", time, suffix));
+        let file = match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            file => file.context(format!("creating provenance manifest <{}>", path.display()))?,
+        };
+        return Ok((path, file));
+    }
+    unreachable!("there is always another suffix to try")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use utils::TempDir;
+
+    #[test]
+    fn runs_in_the_same_second_get_their_own_manifests() {
+        let dir = TempDir::new("provenance");
+        let (first, _) = create(dir.path(), "20240102T030405").unwrap();
+        let (second, _) = create(dir.path(), "20240102T030405").unwrap();
+        assert_eq!(first, dir.join("sd3norm-provenance-20240102T030405.json"));
+        assert_eq!(second, dir.join("sd3norm-provenance-20240102T030405-2.json"));
+    }
+}
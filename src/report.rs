@@ -0,0 +1,179 @@
+use failure::Error;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use si::SIUnit;
+
+/// Counts of what happened to the workbooks, sheets, and rows of a run
+#[derive(Debug, Default, Serialize)]
+pub struct Summary {
+    pub workbooks: usize,
+    /// Workbooks that were not normalized because of "--no-clobber", or because "--incremental" found them unchanged
+    pub workbooks_skipped: usize,
+    pub sheets: usize,
+    /// Sheets that could not be parsed at all
+    pub sheets_skipped: usize,
+    pub rows_read: usize,
+    pub rows_normalized: usize,
+    /// Rows with non-physical normalization info that were output un-normalized
+    pub rows_flagged: usize,
+    pub rows_excluded: usize,
+    /// Rows that could not be normalized, by the kind of error
+    pub rows_rejected: BTreeMap<&'static str, usize>,
+    pub outputs_written: usize,
+    /// The output files that were written
+    #[serde(skip)]
+    pub outputs: Vec<PathBuf>,
+    /// The output files that a dry run would have written, and how many rows each would get
+    #[serde(skip)]
+    pub planned: Vec<(PathBuf, usize)>,
+    /// Workbooks that could not be read or normalized
+    pub failures: Vec<Failure>,
+    /// What happened to the rows of each sheet
+    #[serde(skip)]
+    pub sheet_rows: Vec<SheetRows>,
+    /// The units that normalized values were converted from
+    #[serde(skip)]
+    pub units: BTreeSet<SIUnit>,
+}
+
+/// Counts of what happened to the rows of one sheet
+#[derive(Debug, Clone, Serialize)]
+pub struct SheetRows {
+    pub workbook: PathBuf,
+    pub sheet: String,
+    pub read: usize,
+    pub normalized: usize,
+    pub flagged: usize,
+    pub excluded: usize,
+    pub rejected: usize,
+}
+
+/// A workbook that could not be read or normalized, and why
+#[derive(Debug, Clone, Serialize)]
+pub struct Failure {
+    pub workbook: String,
+    pub error: String,
+}
+
+impl Summary {
+    pub fn reject(&mut self, kind: &'static str) {
+        *self.rows_rejected.entry(kind).or_insert(0) += 1;
+    }
+
+    pub fn wrote(&mut self, outputs: Vec<PathBuf>) {
+        self.outputs_written += outputs.len();
+        self.outputs.extend(outputs);
+    }
+
+    pub fn fail(&mut self, wb_path: &Path, e: &Error) {
+        self.failures.push(Failure { workbook: wb_path.display().to_string(), error: describe(e) });
+    }
+
+    /// Count the rows of a sheet, from the counts of this summary before it was started (from `row_counts`)
+    pub fn finish_sheet(&mut self, wb_path: &Path, sheet: &str, before: [usize; 5]) {
+        let after = self.row_counts();
+        self.sheet_rows.push(SheetRows {
+            workbook: wb_path.to_path_buf(),
+            sheet: sheet.to_string(),
+            read: after[0] - before[0],
+            normalized: after[1] - before[1],
+            flagged: after[2] - before[2],
+            excluded: after[3] - before[3],
+            rejected: after[4] - before[4],
+        });
+    }
+
+    /// The rows read, normalized, flagged, excluded and rejected so far
+    pub fn row_counts(&self) -> [usize; 5] {
+        [self.rows_read, self.rows_normalized, self.rows_flagged, self.rows_excluded, self.total_rejected()]
+    }
+
+    /// Add the counts of another summary, like that of a workbook normalized on another thread
+    pub fn add(&mut self, other: &Summary) {
+        self.workbooks += other.workbooks;
+        self.workbooks_skipped += other.workbooks_skipped;
+        self.sheets += other.sheets;
+        self.sheets_skipped += other.sheets_skipped;
+        self.rows_read += other.rows_read;
+        self.rows_normalized += other.rows_normalized;
+        self.rows_flagged += other.rows_flagged;
+        self.rows_excluded += other.rows_excluded;
+        for (kind, n) in &other.rows_rejected {
+            *self.rows_rejected.entry(kind).or_insert(0) += n;
+        }
+        self.outputs_written += other.outputs_written;
+        self.outputs.extend(other.outputs.iter().cloned());
+        self.planned.extend(other.planned.iter().cloned());
+        self.failures.extend(other.failures.iter().cloned());
+        self.sheet_rows.extend(other.sheet_rows.iter().cloned());
+        self.units.extend(&other.units);
+    }
+
+    pub fn total_rejected(&self) -> usize {
+        self.rows_rejected.values().sum()
+    }
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let line = |f: &mut fmt::Formatter, name: &str, n: usize| writeln!(f, "  {:<24}{:>8}", name, n);
+
+        writeln!(f, "Summary")?;
+        line(f, "Workbooks processed", self.workbooks)?;
+        line(f, "Workbooks skipped", self.workbooks_skipped)?;
+        line(f, "Workbooks failed", self.failures.len())?;
+        line(f, "Sheets processed", self.sheets)?;
+        line(f, "Sheets skipped", self.sheets_skipped)?;
+        line(f, "Rows read", self.rows_read)?;
+        line(f, "Rows normalized", self.rows_normalized)?;
+        line(f, "Rows flagged", self.rows_flagged)?;
+        line(f, "Rows excluded", self.rows_excluded)?;
+        line(f, "Rows rejected", self.total_rejected())?;
+        for (kind, n) in &self.rows_rejected {
+            writeln!(f, "    {:<22}{:>8}", kind, n)?;
+        }
+        line(f, "Outputs written", self.outputs_written)?;
+        if !self.failures.is_empty() {
+            writeln!(f, "Failed workbooks")?;
+        }
+        for failure in &self.failures {
+            writeln!(f, "  {}: {}", failure.workbook, failure.error)?;
+        }
+        Ok(())
+    }
+}
+
+/// An error and its causes on one line, without the causes that only repeat the one before
+pub fn describe(e: &Error) -> String {
+    let mut causes: Vec<_> = e.iter_chain().map(|c| c.to_string()).collect();
+    causes.dedup();
+    causes.join(": ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn failed_workbooks_are_counted_and_listed() {
+        let mut workbook = Summary { workbooks: 1, rows_read: 3, rows_normalized: 2, ..Summary::default() };
+        workbook.reject("NoValue");
+        workbook.fail(Path::new("b.xlsx"), &format_err!("could not open").context("reading b.xlsx").into());
+        let mut total = Summary::default();
+        total.add(&workbook);
+        total.add(&workbook);
+
+        let text = total.to_string();
+        assert!(text.contains("  Workbooks processed            2\n"));
+        assert!(text.contains("  Workbooks failed               2\n"));
+        assert!(text.contains("  Rows rejected                  2\n    NoValue                      2\n"));
+        let failure = "  b.xlsx: reading b.xlsx: could not open\n";
+        assert!(text.ends_with(&format!("Failed workbooks\n{}{}", failure, failure)));
+
+        let json = ::serde_json::to_value(&total).unwrap();
+        assert_eq!(json["rows_rejected"]["NoValue"], 2);
+        assert_eq!(json["failures"][1]["workbook"], "b.xlsx");
+        assert_eq!(json["failures"][1]["error"], "reading b.xlsx: could not open");
+    }
+}
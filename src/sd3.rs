@@ -1,17 +1,221 @@
-use si::{SIUnit, self};
+use calamine::DataType;
+use failure::Error;
+use std::fmt;
+use std::str::FromStr;
+use si::{SIUnit, SIError, UnitType, self};
 use mifc::MIFC;
 
 #[derive(Debug, Fail)]
 pub enum SD3Error {
     #[fail(display = "row had a non-empty Exclude column")]
-    Excluded,
+    Excluded(RowContext),
     #[fail(display = "row did not have associated normalization info columns")]
-    NoInfo,
+    NoInfo(RowContext),
     #[fail(display = "row did not have an entered Value")]
-    NoValue,
+    NoValue(RowContext),
     #[fail(display = "row did not have an entered Value Unit")]
-    NoValueUnit,
+    NoValueUnit(RowContext),
+    #[fail(display = "could not read row: {}", message)]
+    Deserialize {
+        ctx: RowContext,
+        column: Option<&'static str>,
+        message: String,
+    },
+    #[fail(display = "sample collection duration of {} days is not positive", days)]
+    NonPositiveDuration { ctx: RowContext, days: f64 },
+    #[fail(display = "sample volume of {} is not positive", volume)]
+    NonPositiveVolume { ctx: RowContext, volume: f64 },
+    #[fail(display = "estimated cell number of {} is not positive", cells)]
+    NonPositiveCellCount { ctx: RowContext, cells: f64 },
+    #[fail(display = "Value of {} is negative", value)]
+    NegativeValue { ctx: RowContext, value: f64 },
+    #[fail(display = "{} is a {} unit, but {} requires a {} unit", unit, found, column, expected)]
+    WrongUnitDimension {
+        ctx: RowContext,
+        column: &'static str,
+        unit: SIUnit,
+        found: UnitType,
+        expected: UnitType,
+    },
+    #[fail(display = "normalized value was not a finite number")]
+    NotANumber(RowContext),
 }
+
+impl SD3Error {
+    /// The sheet and row that caused this error
+    pub fn context(&self) -> &RowContext {
+        use self::SD3Error::*;
+        match self {
+            Excluded(ctx) | NoInfo(ctx) | NoValue(ctx) | NoValueUnit(ctx) | NotANumber(ctx)
+                => ctx,
+            Deserialize { ctx, .. } | NonPositiveDuration { ctx, .. } | NonPositiveVolume { ctx, .. }
+            | NonPositiveCellCount { ctx, .. } | NegativeValue { ctx, .. } | WrongUnitDimension { ctx, .. }
+                => ctx,
+        }
+    }
+    /// The name of the column responsible for this error, if there is a single one
+    pub fn column(&self) -> Option<&'static str> {
+        use self::SD3Error::*;
+        match self {
+            Excluded(_) => Some("Exclude"),
+            NoValue(_) | NegativeValue { .. } | NotANumber(_) => Some("Value"),
+            NoValueUnit(_) => Some("Value Unit"),
+            NonPositiveVolume { .. } => Some("Sample Volume"),
+            NonPositiveCellCount { .. } => Some("Estimated Cell Number"),
+            Deserialize { column, .. } => *column,
+            WrongUnitDimension { column, .. } => Some(column),
+            NoInfo(_) | NonPositiveDuration { .. } => None,
+        }
+    }
+    /// A short name for the kind of error, used to group rejected rows
+    pub fn kind(&self) -> &'static str {
+        use self::SD3Error::*;
+        match self {
+            Excluded(_) => "Excluded",
+            NoInfo(_) => "NoInfo",
+            NoValue(_) => "NoValue",
+            NoValueUnit(_) => "NoValueUnit",
+            Deserialize { .. } => "Deserialize",
+            NonPositiveDuration { .. } => "NonPositiveDuration",
+            NonPositiveVolume { .. } => "NonPositiveVolume",
+            NonPositiveCellCount { .. } => "NonPositiveCellCount",
+            NegativeValue { .. } => "NegativeValue",
+            WrongUnitDimension { .. } => "WrongUnitDimension",
+            NotANumber(_) => "NotANumber",
+        }
+    }
+    /// If this error was caused by normalization info or a Value that can't give a physical result
+    pub fn is_non_physical(&self) -> bool {
+        use self::SD3Error::*;
+        matches!(self,
+            NonPositiveDuration { .. } | NonPositiveVolume { .. } | NonPositiveCellCount { .. }
+            | NegativeValue { .. } | NotANumber(_)
+        )
+    }
+}
+
+/// What to do with a row that has non-physical normalization info, e.g. zero cells
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum InvalidPolicy {
+    /// Do not output the row
+    Reject,
+    /// Output the row with an empty Value, and the reason in its Caution Flag
+    Flag,
+    /// Output the row with its original Value and Value Unit, and the reason in its Caution Flag
+    Pass,
+}
+
+impl FromStr for InvalidPolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reject" => Ok(InvalidPolicy::Reject),
+            "flag" => Ok(InvalidPolicy::Flag),
+            "pass" => Ok(InvalidPolicy::Pass),
+            _ => Err(format_err!("unknown policy <{}>, expected one of reject, flag, or pass", s)),
+        }
+    }
+}
+
+impl fmt::Display for InvalidPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            InvalidPolicy::Reject => "reject",
+            InvalidPolicy::Flag => "flag",
+            InvalidPolicy::Pass => "pass",
+        })
+    }
+}
+
+/// Location of an SD3 row within a workbook
+#[derive(Debug, Clone)]
+pub struct RowContext {
+    pub sheet: String,
+    /// Worksheet row number, as shown in Excel (1-indexed)
+    pub row: u32,
+}
+
+impl fmt::Display for RowContext {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "sheet <{}> row {}", self.sheet, self.row)
+    }
+}
+
+/// The kind of value held by an SD3 column
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Expect {
+    Text,
+    Number,
+    Unit,
+}
+
+impl Expect {
+    fn describe(self) -> &'static str {
+        match self {
+            Expect::Text => "text",
+            Expect::Number => "a number",
+            Expect::Unit => "a unit",
+        }
+    }
+}
+
+/// An SD3 column header, the kind of value it holds, and if it must have a value
+pub struct Column(pub &'static str, pub Expect, pub bool);
+
+pub static MIFC_COLUMNS: [Column; 17] = [
+    Column("Chip ID", Expect::Text, true),
+    Column("Assay Plate ID", Expect::Text, false),
+    Column("Assay Well ID", Expect::Text, false),
+    Column("Method/Kit", Expect::Text, true),
+    Column("Target/Analyte", Expect::Text, true),
+    Column("Subtarget", Expect::Text, false),
+    Column("Sample Location", Expect::Text, true),
+    Column("Day", Expect::Number, true),
+    Column("Hour", Expect::Number, true),
+    Column("Minute", Expect::Number, true),
+    Column("Value", Expect::Number, false),
+    Column("Value Unit", Expect::Unit, false),
+    Column("Caution Flag", Expect::Text, false),
+    Column("Exclude", Expect::Text, false),
+    Column("Notes", Expect::Text, false),
+    Column("Replicate", Expect::Number, false),
+    Column("Cross Reference", Expect::Text, false),
+];
+
+pub static NORMALIZATION_COLUMNS: [Column; 6] = [
+    Column("Duration Sample Collection (days)", Expect::Number, true),
+    Column("Duration Sample Collection (hours)", Expect::Number, true),
+    Column("Duration Sample Collection (minutes)", Expect::Number, true),
+    Column("Sample Volume", Expect::Number, true),
+    Column("Sample Volume Unit", Expect::Unit, true),
+    Column("Estimated Cell Number", Expect::Number, true),
+];
+
+impl Column {
+    /// Describe why a cell does not hold the kind of value expected in this column
+    pub fn check(&self, cell: &DataType) -> Option<String> {
+        let &Column(_, expect, required) = self;
+        match (cell, expect) {
+            (DataType::Error(e), _) => Some(format!("cell contains the error {}", e)),
+            (DataType::Empty, _) if required => Some(format!("expected {}, found an empty cell", expect.describe())),
+            (DataType::Empty, _) => None,
+            (DataType::Float(_), Expect::Number) | (DataType::Int(_), Expect::Number) => None,
+            (DataType::String(_), Expect::Text) => None,
+            (DataType::String(s), Expect::Unit) => s.parse::<SIUnit>().err().map(|e| e.to_string()),
+            (other, _) => Some(format!("expected {}, found \"{}\"", expect.describe(), other)),
+        }
+    }
+}
+
+/// A row that will be output
+pub enum Outcome {
+    /// The row's Value was normalized
+    Normalized(MIFC),
+    /// The row had non-physical normalization info, and was flagged instead of normalized
+    Flagged(MIFC),
+}
+
 //TODO: Deserialize optional string fields with a null || "" = None checking function
 #[derive(Debug, Serialize, Deserialize)]
 pub struct SD3 {
@@ -21,17 +225,41 @@ pub struct SD3 {
     normal_info: Option<Normalization>,
 }
 
+/// The units that every normalized Value is converted through, on the way to ng/day/10^6 cells
+const CONVERSION_UNITS: [SIUnit; 4] = [SIUnit::g_l, SIUnit::l, SIUnit::g, SIUnit::ng];
+
 impl SD3 {
-    pub fn into_normalized(self) -> Result<MIFC, SD3Error> {
+    /// The units that this row's Value and Sample Volume are converted from, and through, when it is normalized
+    pub fn units(&self) -> Vec<SIUnit> {
+        self.mifc.value_unit.iter()
+            .cloned()
+            .chain(self.normal_info.as_ref().map(|n| n.sample_vol_unit))
+            .chain(CONVERSION_UNITS.iter().cloned())
+            .collect()
+    }
+
+    pub fn into_normalized(self, ctx: &RowContext, policy: InvalidPolicy) -> Result<Outcome, SD3Error> {
         if let Some(ref f) = self.mifc.exclude {
-            if f != "" { return Err(SD3Error::Excluded) }
+            if !f.is_empty() { return Err(SD3Error::Excluded(ctx.clone())) }
         }
-        let value = self.mifc.value.ok_or(SD3Error::NoValue)?;
-        let value_unit = self.mifc.value_unit.ok_or(SD3Error::NoValueUnit)?;
-        let info = self.normal_info.ok_or(SD3Error::NoInfo)?;
+        let value = self.mifc.value.ok_or_else(|| SD3Error::NoValue(ctx.clone()))?;
+        let value_unit = self.mifc.value_unit.ok_or_else(|| SD3Error::NoValueUnit(ctx.clone()))?;
+        let info = self.normal_info.ok_or_else(|| SD3Error::NoInfo(ctx.clone()))?;
 
         let sample_time = info.calc_sample_time();
-        let norm_val = to_ngday_millioncells(value, value_unit, &info);
+        let norm_val = match to_ngday_millioncells(value, value_unit, &info, ctx) {
+            Ok(v) => v,
+            Err(ref e) if e.is_non_physical() && policy != InvalidPolicy::Reject => {
+                let mut flagged = self.mifc;
+                if policy == InvalidPolicy::Flag {
+                    flagged.value = None;
+                    flagged.value_unit = None;
+                }
+                append_text(&mut flagged.flag, &format!("Not normalized: {}", e));
+                return Ok(Outcome::Flagged(flagged));
+            },
+            Err(e) => return Err(e),
+        };
 
         let mut normalized_mifc = self.mifc;
         let note = format!("Normalized from {v:.4} {vu} by a {s} {su} sample over {d} {ds} with an estimated {c} cells ", 
@@ -43,15 +271,20 @@ impl SD3 {
 
         normalized_mifc.value = Some(norm_val);
         normalized_mifc.value_unit = Some(SIUnit::ng_day_millioncells);        
-        normalized_mifc.notes = if let Some(mut n) = normalized_mifc.notes {
-            if &n != "" { n.push_str(" || "); }
-            n.push_str(&note);
-            Some(n)
-        } else {
-            Some(note)
-        };
+        append_text(&mut normalized_mifc.notes, &note);
 
-        Ok(normalized_mifc)
+        Ok(Outcome::Normalized(normalized_mifc))
+    }
+}
+
+/// Add text to an optional text column, separating it from any existing text
+fn append_text(field: &mut Option<String>, text: &str) {
+    match field {
+        Some(f) => {
+            if !f.is_empty() { f.push_str(" || "); }
+            f.push_str(text);
+        },
+        None => *field = Some(text.to_string()),
     }
 }
 
@@ -79,27 +312,66 @@ impl Normalization {
         + (self.sample_hours/24.0) 
         + (self.sample_minutes/(24.0*60.0))
     }
+    /// Check that this info describes a physical sample that a value can be normalized by
+    fn validate(&self, ctx: &RowContext) -> Result<(), SD3Error> {
+        let is_positive = |x: f64| x.is_finite() && x > 0.0;
+
+        let days = self.calc_sample_time();
+        if !is_positive(days) {
+            return Err(SD3Error::NonPositiveDuration { ctx: ctx.clone(), days });
+        }
+        if !is_positive(self.sample_volume) {
+            return Err(SD3Error::NonPositiveVolume { ctx: ctx.clone(), volume: self.sample_volume });
+        }
+        if !is_positive(self.cell_count) {
+            return Err(SD3Error::NonPositiveCellCount { ctx: ctx.clone(), cells: self.cell_count });
+        }
+        Ok(())
+    }
 }
 
-fn to_ngday_millioncells(val: f64, val_unit: SIUnit, norm: &Normalization) -> f64
+/// Attribute a failed unit conversion to the column that the unit was entered in
+fn unit_error(e: SIError, unit: SIUnit, column: &'static str, ctx: &RowContext) -> SD3Error {
+    match e {
+        SIError::IncompatibleTypes(found, expected)
+            => SD3Error::WrongUnitDimension { ctx: ctx.clone(), column, unit, found, expected },
+        SIError::UnkType(_)
+            => SD3Error::Deserialize { ctx: ctx.clone(), column: Some(column), message: e.to_string() },
+    }
+}
+
+fn to_ngday_millioncells(val: f64, val_unit: SIUnit, norm: &Normalization, ctx: &RowContext)
+    -> Result<f64, SD3Error>
 {
     use self::SIUnit::*;
 
     let &Normalization{cell_count: cells, sample_volume: vol, sample_vol_unit: vol_unit, ..} = norm;
 
     let days = norm.calc_sample_time();
-    let si_val = si::convert((val, val_unit), g_l).unwrap();
-    let si_vol = si::convert((vol, vol_unit), l).unwrap();
+    if val < 0.0 {
+        return Err(SD3Error::NegativeValue { ctx: ctx.clone(), value: val });
+    }
+    norm.validate(ctx)?;
+    let si_val = si::convert((val, val_unit), g_l)
+        .map_err(|e| unit_error(e, val_unit, "Value Unit", ctx))?;
+    let si_vol = si::convert((vol, vol_unit), l)
+        .map_err(|e| unit_error(e, vol_unit, "Sample Volume Unit", ctx))?;
     trace!("conc: {:.5} {} to SI {:.5} {}", val, val_unit, si_val, g_l);
     trace!("vol: {:.5} {} to SI {:.5} {}",  vol, vol_unit, si_vol, l);
 
     // first go from the concentration (g/L) and sample volume (L) 
     // into nanograms/day/cell
-    let made_ng = si::convert((si_val * si_vol, g), ng).unwrap();
+    let made_ng = si::convert((si_val * si_vol, g), ng)
+        .map_err(|e| unit_error(e, g, "Value Unit", ctx))?;
     trace!("produced ng: {:.5} over {:.3} day(s)", made_ng, days);
     let ngdaycell = made_ng / days / cells;
     // now, multiple by 10^6 to make rate by million cells 
-    ngdaycell * 1_000_000.0
+    let ngday_millioncells = ngdaycell * 1_000_000.0;
+    if !ngday_millioncells.is_finite() {
+        return Err(SD3Error::NotANumber(ctx.clone()));
+    }
+
+    Ok(ngday_millioncells)
 }
 
 #[cfg(test)]
@@ -250,12 +522,16 @@ mod tests {
         0.02232,
     ];
 
+    fn ctx() -> RowContext {
+        RowContext { sheet: "Sheet1".to_string(), row: 2 }
+    }
+
     #[test]
     fn ng_day_cell_normalization() {
         const PERCENT_TOLERANCE: f64 = 0.001;
 
         let all_equal = INPUTS.iter()
-            .map(|i| to_ngday_millioncells(i.val, i.val_unit, &i.info))
+            .map(|i| to_ngday_millioncells(i.val, i.val_unit, &i.info, &ctx()).unwrap())
             .zip(OUTPUTS.iter())
             .enumerate()
             .inspect(|(i, (c, e))|
@@ -267,4 +543,118 @@ mod tests {
 
         assert!(all_equal);
     }
+
+    #[test]
+    fn non_physical_inputs_are_errors() {
+        let info = |days: f64, vol_unit: SIUnit, cells: f64| Normalization {
+            sample_days: days,
+            sample_hours: 0.0,
+            sample_minutes: 0.0,
+            sample_volume: 200.0,
+            sample_vol_unit: vol_unit,
+            cell_count: cells,
+        };
+
+        match to_ngday_millioncells(10.0, ng_ml, &info(0.0, ul, 20000.0), &ctx()) {
+            Err(SD3Error::NonPositiveDuration { .. }) => (),
+            other => panic!("zero duration gave {:?}", other),
+        }
+        match to_ngday_millioncells(10.0, ng_ml, &info(1.0, ul, 0.0), &ctx()) {
+            Err(SD3Error::NonPositiveCellCount { .. }) => (),
+            other => panic!("zero cells gave {:?}", other),
+        }
+        let mut no_volume = info(1.0, ul, 20000.0);
+        no_volume.sample_volume = 0.0;
+        match to_ngday_millioncells(10.0, ng_ml, &no_volume, &ctx()) {
+            Err(SD3Error::NonPositiveVolume { .. }) => (),
+            other => panic!("zero volume gave {:?}", other),
+        }
+        match to_ngday_millioncells(-10.0, ng_ml, &info(1.0, ul, 20000.0), &ctx()) {
+            Err(SD3Error::NegativeValue { .. }) => (),
+            other => panic!("negative value gave {:?}", other),
+        }
+        match to_ngday_millioncells(10.0, ml, &info(1.0, ul, 20000.0), &ctx()) {
+            Err(e @ SD3Error::WrongUnitDimension { .. }) => assert_eq!(e.column(), Some("Value Unit")),
+            other => panic!("volume Value Unit gave {:?}", other),
+        }
+        match to_ngday_millioncells(10.0, ng_ml, &info(1.0, ng_ml, 20000.0), &ctx()) {
+            Err(e @ SD3Error::WrongUnitDimension { .. }) => assert_eq!(e.column(), Some("Sample Volume Unit")),
+            other => panic!("concentration Sample Volume Unit gave {:?}", other),
+        }
+    }
+
+    #[test]
+    fn cell_checks() {
+        let number = Column("Day", Expect::Number, true);
+        let opt_number = Column("Value", Expect::Number, false);
+        let unit = Column("Value Unit", Expect::Unit, false);
+        let text = Column("Chip ID", Expect::Text, true);
+
+        assert!(number.check(&DataType::Float(1.0)).is_none());
+        assert!(number.check(&DataType::Int(80_000)).is_none());
+        assert!(opt_number.check(&DataType::Empty).is_none());
+        assert!(unit.check(&DataType::String("uL".into())).is_none());
+
+        assert!(number.check(&DataType::Empty).is_some());
+        assert!(number.check(&DataType::String("1 day".into())).is_some());
+        assert!(unit.check(&DataType::String("ug/ml".into())).is_some());
+        assert!(text.check(&DataType::Float(12.0)).is_some());
+    }
+
+    #[test]
+    fn non_physical_policies() {
+        let zero_cells = || SD3 {
+            mifc: MIFC {
+                id: "chip".to_string(),
+                assay_plate_id: None,
+                assay_well_id: None,
+                method: "ELISA".to_string(),
+                target: "Albumin".to_string(),
+                subtarget: None,
+                sample_loc: "Efflux".to_string(),
+                day: 1.0,
+                hour: 0.0,
+                min: 0.0,
+                value: Some(10.0),
+                value_unit: Some(ng_ml),
+                flag: None,
+                exclude: None,
+                notes: None,
+                replicate: None,
+                xref: None,
+            },
+            normal_info: Some(Normalization {
+                sample_days: 1.0,
+                sample_hours: 0.0,
+                sample_minutes: 0.0,
+                sample_volume: 200.0,
+                sample_vol_unit: ul,
+                cell_count: 0.0,
+            }),
+        };
+
+        assert!(zero_cells().into_normalized(&ctx(), InvalidPolicy::Reject).is_err());
+
+        let flagged = match zero_cells().into_normalized(&ctx(), InvalidPolicy::Flag) {
+            Ok(Outcome::Flagged(m)) => m,
+            _ => panic!("zero cells were not flagged"),
+        };
+        assert_eq!(flagged.value, None);
+        assert_eq!(flagged.value_unit, None);
+        assert!(flagged.flag.is_some());
+
+        let passed = match zero_cells().into_normalized(&ctx(), InvalidPolicy::Pass) {
+            Ok(Outcome::Flagged(m)) => m,
+            _ => panic!("zero cells were not passed through"),
+        };
+        assert_eq!(passed.value, Some(10.0));
+        assert_eq!(passed.value_unit, Some(ng_ml));
+        assert!(passed.flag.is_some());
+    }
+
+    #[test]
+    fn mifc_columns_are_written_in_the_order_they_are_read() {
+        let read: Vec<_> = MIFC_COLUMNS.iter().map(|c| c.0).collect();
+        assert_eq!(read, MIFC::HEADERS);
+    }
 }
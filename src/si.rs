@@ -5,16 +5,17 @@ use serde::ser::{Serialize, Serializer};
 
 #[derive(Debug, Fail)]
 pub enum SIError {
-    #[fail(display = "Cannot convert from {:?} to {:?}", _0, _1)]
+    #[fail(display = "can't convert a {} unit to a {} unit", _0, _1)]
     IncompatibleTypes(UnitType, UnitType),
     #[fail(display = "Unknown SI unit <{}>", _0)]
     UnkType(String),
 }
 
-#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd)]
 #[allow(non_camel_case_types)]
 pub enum SIUnit {
     pg_ml,
+    pg_ul,
     ng_ml,
     mg_ml,
     mg_dl,
@@ -36,12 +37,21 @@ pub enum SIUnit {
     ng_day_millioncells,
 }
 
+/// Every unit that can be read
+pub const UNITS: [SIUnit; 17] = [
+    SIUnit::pg_ml, SIUnit::pg_ul, SIUnit::ng_ml, SIUnit::mg_ml, SIUnit::mg_dl, SIUnit::g_l,
+    SIUnit::ml, SIUnit::ul, SIUnit::dl, SIUnit::l,
+    SIUnit::ng, SIUnit::g,
+    SIUnit::g_day, SIUnit::ng_day,
+    SIUnit::g_day_cell, SIUnit::ng_day_cell, SIUnit::ng_day_millioncells,
+];
+
 impl SIUnit {
-    fn unit_type(&self) -> UnitType {
+    pub fn unit_type(&self) -> UnitType {
         use self::SIUnit::*;
         use self::UnitType::*;
         match self {
-            pg_ml | ng_ml | mg_ml | mg_dl | g_l
+            pg_ml | pg_ul | ng_ml | mg_ml | mg_dl | g_l
                 => Concentration,
             ul | ml | dl | l 
                 => Volume,
@@ -67,11 +77,12 @@ impl SIUnit {
         }
     }*/
 
-    fn as_str(&self) -> &'static str {
+    pub fn as_str(&self) -> &'static str {
         use self::SIUnit::*;
 
         match self {
             pg_ml => "pg/mL",
+            pg_ul => "pg/µL",
             ng_ml => "ng/mL",
             mg_ml => "mg/mL",
             mg_dl => "mg/dL",
@@ -93,12 +104,42 @@ impl SIUnit {
             ng_day_millioncells => "ng/day/10^6 cells",
         }
     }
+    /// Every name that this unit is read from, starting with the one it's written as
+    pub fn names(&self) -> &'static [&'static str] {
+        use self::SIUnit::*;
+
+        match self {
+            pg_ml => &["pg/mL", "pg/ml"],
+            pg_ul => &["pg/µL", "pg/µl", "pg/μL", "pg/μl", "pg/uL", "pg/ul"],
+            ng_ml => &["ng/mL", "ng/ml"],
+            mg_ml => &["mg/mL", "mg/ml"],
+            mg_dl => &["mg/dL", "mg/dl"],
+            g_l => &["g/L", "g/l"],
+
+            ml => &["mL", "ml"],
+            ul => &["µL", "µl", "μL", "μl", "ul", "uL"],
+            dl => &["dL", "dl"],
+            l => &["L", "l"],
+
+            g => &["g"],
+            ng => &["ng"],
+
+            g_day => &["g/day"],
+            ng_day => &["ng/day"],
+
+            g_day_cell => &["g/day/cell"],
+            ng_day_cell => &["ng/day/cell"],
+            ng_day_millioncells => &["ng/day/10^6 cells", "ng/day/10^6cells"],
+        }
+    }
+
     /// Factor to put this unit into base SI unit
-    fn si_factor(&self) -> f64 {
+    pub fn si_factor(&self) -> f64 {
         use self::SIUnit::*;
 
         match self {
             pg_ml => 1e-9,
+            pg_ul => 1e-6,
             ng_ml => 1e-6,
             mg_ml => 1.0,
             mg_dl => 1e-2,
@@ -132,32 +173,10 @@ impl FromStr for SIUnit {
     type Err = SIError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        use self::SIUnit::*;
-
-        match s {
-            "pg/mL" | "pg/ml" => Ok(pg_ml),
-            "ng/mL" | "ng/ml" => Ok(ng_ml),
-            "mg/mL" | "mg/ml" => Ok(mg_ml),
-            "mg/dL" | "mg/dl" => Ok(mg_dl),
-            "g/L" | "g/l" => Ok(g_l),
-
-            "mL" | "ml" => Ok(ml),
-            "µL" | "µl" | "ul" | "uL" => Ok(ul),
-            "dL" | "dl" => Ok(dl),
-            "L" | "l" => Ok(l),
-
-            "g" => Ok(g),
-            "ng" => Ok(ng),
-
-            "g/day" => Ok(g_day),
-            "ng/day" => Ok(ng_day),
-
-            "g/day/cell" => Ok(g_day_cell),
-            "ng/day/cell" => Ok(ng_day_cell),
-            "ng/day/10^6 cells" | "ng/day/10^6cells" => Ok(ng_day_millioncells),
-            
-            _ => Err(SIError::UnkType(s.to_string())),
-        }
+        UNITS.iter()
+            .find(|u| u.names().contains(&s))
+            .cloned()
+            .ok_or_else(|| SIError::UnkType(s.to_string()))
     }
 }
 
@@ -193,6 +212,7 @@ impl<'de> Visitor<'de> for SIUnitVisitor {
     }
 }
 
+/// The kind of quantity that a unit measures. Values can only be converted between units of the same type
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub enum UnitType {
     Concentration,
@@ -202,6 +222,18 @@ pub enum UnitType {
     Mass,
 }
 
+impl fmt::Display for UnitType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            UnitType::Concentration => "concentration",
+            UnitType::Volume => "volume",
+            UnitType::CellNormalized => "cell-normalized rate",
+            UnitType::Rate => "rate",
+            UnitType::Mass => "mass",
+        })
+    }
+}
+
 pub fn convert((val, unit): (f64, SIUnit), to: SIUnit) -> Result<f64, SIError> {
     let from_type = unit.unit_type();
     let to_type = to.unit_type();
@@ -249,5 +281,18 @@ mod tests {
         assert!(double_comparable(convert((20.0, ng_ml), g_l).unwrap(), 20e-6, TOL), "20 ug_ml to g_l");
         assert!(double_comparable(convert((32.0, mg_ml), g_l).unwrap(), 32.0, TOL), "32 mg_ml to g_l");
         assert!(double_comparable(convert((1.0, mg_dl), g_l).unwrap(), 1e-2, TOL), "1 mg_dl to g_l");
+        assert!(double_comparable(convert((150.0, ng_ml), pg_ul).unwrap(), 150.0, TOL), "150 ng_ml to pg_ul");
+    }
+
+    #[test]
+    fn units_are_read_from_every_name() {
+        for unit in &UNITS {
+            assert_eq!(unit.names()[0], unit.as_str());
+            for name in unit.names() {
+                assert_eq!(name.parse::<SIUnit>().ok(), Some(*unit), "{}", name);
+            }
+        }
+        assert!("ug/ml".parse::<SIUnit>().is_err());
+        assert!(convert((1.0, SIUnit::ml), SIUnit::ng).is_err());
     }
 }
@@ -0,0 +1,154 @@
+use failure::{Error, ResultExt};
+use rusqlite::{Connection, params_from_iter};
+use rusqlite::types::Value as SqlValue;
+use std::path::{Path, PathBuf};
+use mifc::{MIFC, Value};
+use sd3::RowContext;
+use output::Writer;
+use workbook::Sheet;
+
+/// Table that normalized rows are written into
+const TABLE: &str = "mifc";
+
+/// The SQL type of each MIFC column, in the same order as `MIFC::HEADERS`
+const MIFC_TYPES: [&str; 17] = [
+    "TEXT NOT NULL", "TEXT", "TEXT", "TEXT NOT NULL", "TEXT NOT NULL", "TEXT", "TEXT NOT NULL",
+    "REAL NOT NULL", "REAL NOT NULL", "REAL NOT NULL", "REAL", "TEXT", "TEXT", "TEXT", "TEXT",
+    "REAL", "TEXT",
+];
+
+/// Columns that identify a measurement, so that re-normalizing it replaces the old row
+const KEY: [&str; 7] = ["Chip ID", "Target/Analyte", "Sample Location", "Day", "Hour", "Minute", "Replicate"];
+
+/// Extra columns recording where each row came from, and when it was normalized
+const PROVENANCE: [(&str, &str); 4] = [
+    ("Source File", "TEXT NOT NULL"),
+    ("Source Sheet", "TEXT NOT NULL"),
+    ("Source Row", "INTEGER NOT NULL"),
+    ("Normalized At", "TEXT NOT NULL"),
+];
+
+/// Writes the normalized rows of every workbook in a run into one SQLite database.
+/// The rows of each workbook are committed together once the workbook is done
+pub struct SqliteWriter {
+    path: PathBuf,
+    conn: Connection,
+    workbook: Option<String>,
+}
+
+impl SqliteWriter {
+    /// Open (or create) the database at `path`, creating the table if it doesn't exist yet
+    pub fn open(path: &Path) -> Result<Self, Error> {
+        let conn = Connection::open(path)
+            .context(format!("opening sqlite database <{}>", path.display()))?;
+
+        let columns: Vec<_> = MIFC::HEADERS.iter()
+            .zip(MIFC_TYPES.iter())
+            .map(|(name, ty)| (*name, *ty))
+            .chain(PROVENANCE.iter().cloned())
+            .map(|(name, ty)| format!("{} {}", quote(name), ty))
+            .collect();
+        let key: Vec<_> = KEY.iter().map(|k| quote(k)).collect();
+        conn.execute_batch(&format!(
+            "CREATE TABLE IF NOT EXISTS {t} ({c});\n\
+             CREATE INDEX IF NOT EXISTS {t}_identity ON {t} ({k});",
+            t = TABLE,
+            c = columns.join(", "),
+            k = key.join(", "),
+        )).context(format!("creating table \"{}\" in <{}>", TABLE, path.display()))?;
+
+        Ok(SqliteWriter { path: path.to_path_buf(), conn, workbook: None })
+    }
+
+    fn commit(&mut self) -> Result<(), Error> {
+        if self.workbook.take().is_some() {
+            self.conn.execute_batch("COMMIT")?;
+        }
+        Ok(())
+    }
+}
+
+impl Writer for SqliteWriter {
+    fn start_workbook(&mut self, wb_path: &Path) -> Result<(), Error> {
+        self.commit()?;
+        self.conn.execute_batch("BEGIN")?;
+        self.workbook = Some(wb_path.to_string_lossy().into_owned());
+        Ok(())
+    }
+
+    fn start_sheet(&mut self, _sheet: &Sheet) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn write(&mut self, ctx: &RowContext, row: &MIFC) -> Result<(), Error> {
+        let workbook = self.workbook.clone().ok_or_else(|| format_err!("no workbook was started"))?;
+        let values: Vec<SqlValue> = row.values().iter().map(|v| match *v {
+            Value::Text(s) => SqlValue::Text(s.to_string()),
+            Value::Number(n) => SqlValue::Real(n),
+            Value::Empty => SqlValue::Null,
+        }).collect();
+
+        /* `IS` also matches rows with an empty key column, like a missing Replicate */
+        let identity: Vec<_> = KEY.iter().map(|k| format!("{} IS ?", quote(k))).collect();
+        let key_values = KEY.iter()
+            .map(|k| values[MIFC::HEADERS.iter().position(|h| h == k).unwrap()].clone());
+        self.conn.prepare_cached(&format!("DELETE FROM {} WHERE {}", TABLE, identity.join(" AND ")))?
+            .execute(params_from_iter(key_values))?;
+
+        let columns: Vec<_> = MIFC::HEADERS.iter()
+            .chain(PROVENANCE.iter().map(|(name, _)| name))
+            .map(|c| quote(c))
+            .collect();
+        let placeholders = vec!["?"; MIFC::HEADERS.len() + 3].join(", ");
+        let provenance = vec![SqlValue::Text(workbook), SqlValue::Text(ctx.sheet.clone()), SqlValue::Integer(ctx.row.into())];
+        self.conn.prepare_cached(&format!(
+            "INSERT INTO {} ({}) VALUES ({}, datetime('now'))",
+            TABLE, columns.join(", "), placeholders,
+        ))?.execute(params_from_iter(values.into_iter().chain(provenance)))?;
+        Ok(())
+    }
+
+    fn finish(mut self: Box<Self>) -> Result<Vec<PathBuf>, Error> {
+        self.commit()
+            .context(format!("writing to sqlite database <{}>", self.path.display()))?;
+        info!("Output database: {:?}", &self.path);
+        Ok(vec![self.path])
+    }
+}
+
+/// Quote a column name, which may contain spaces or slashes, for use in SQL
+fn quote(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use utils::{TempDir, mifc_row};
+
+    fn row(id: &str, value: f64, replicate: Option<f32>) -> MIFC {
+        MIFC { replicate, ..mifc_row(id, Some(value)) }
+    }
+
+    #[test]
+    fn rows_are_upserted_by_identity() {
+        let dir = TempDir::new("sqlite");
+        let path = dir.join("mifc.sqlite");
+        let ctx = RowContext { sheet: "Sheet1".to_string(), row: 2 };
+        for value in &[1.0, 2.0] {
+            let mut writer = Box::new(SqliteWriter::open(&path).unwrap());
+            writer.start_workbook(Path::new("study.xlsx")).unwrap();
+            writer.write(&ctx, &row("A1", *value, Some(1.0))).unwrap();
+            writer.write(&ctx, &row("A1", *value, Some(2.0))).unwrap();
+            writer.write(&ctx, &row("A2", *value, None)).unwrap();
+            writer.finish().unwrap();
+        }
+
+        let conn = Connection::open(&path).unwrap();
+        let (count, total): (i64, f64) = conn
+            .query_row("SELECT count(*), sum(\"Value\") FROM mifc", [], |r| Ok((r.get(0)?, r.get(1)?)))
+            .unwrap();
+        assert_eq!(count, 3);
+        assert_eq!(total, 6.0);
+    }
+}
@@ -0,0 +1,45 @@
+use failure::{Error, ResultExt};
+use rust_xlsxwriter::{DataValidation, Format, Note, Workbook};
+use std::path::Path;
+use sd3::{Expect, MIFC_COLUMNS, NORMALIZATION_COLUMNS};
+use si::{self, UnitType};
+
+/// Rows of the template's worksheet that get a list of units to pick from
+const UNIT_ROWS: u32 = 1000;
+
+/// Write a blank SD3 workbook to `path`, with a header row of every column that is read. Required columns
+/// get a note saying so, and unit columns a list of the units that they can be normalized from. A ".csv" `path` gets only the header
+pub fn write(path: &Path) -> Result<(), Error> {
+    let columns: Vec<_> = MIFC_COLUMNS.iter().chain(NORMALIZATION_COLUMNS.iter()).collect();
+
+    if path.extension().is_some_and(|e| e.eq_ignore_ascii_case("csv")) {
+        let mut out = csv::Writer::from_path(path).context(format!("creating template <{}>", path.display()))?;
+        out.write_record(columns.iter().map(|c| c.0))?;
+        out.flush()?;
+        return Ok(());
+    }
+
+    let mut workbook = Workbook::new();
+    let ws = workbook.add_worksheet().set_name("SD3")?;
+    let bold = Format::new().set_bold();
+    for (col, column) in columns.iter().enumerate() {
+        let col = col as u16;
+        ws.write_string_with_format(0, col, column.0, &bold)?;
+        ws.set_column_width(col, column.0.len().max(10) as f64 + 2.0)?;
+        if column.2 {
+            ws.insert_note(0, col, &Note::new("Required").add_author_prefix(false))?;
+        }
+        if column.1 == Expect::Unit {
+            /* Only a concentration can be normalized by the volume that it was sampled in */
+            let kind = if column.0 == "Sample Volume Unit" { UnitType::Volume } else { UnitType::Concentration };
+            let units: Vec<_> = si::UNITS.iter()
+                .filter(|u| u.unit_type() == kind)
+                .map(|u| u.as_str())
+                .collect();
+            ws.add_data_validation(1, col, UNIT_ROWS, col, &DataValidation::new().allow_list_strings(&units)?)?;
+        }
+    }
+    ws.set_freeze_panes(1, 0)?;
+    workbook.save(path).context(format!("writing template <{}>", path.display()))?;
+    Ok(())
+}
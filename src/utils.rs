@@ -1,3 +1,11 @@
+use mifc::MIFC;
+use sd3::{MIFC_COLUMNS, NORMALIZATION_COLUMNS};
+use si::SIUnit;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+
 /// Compare doubles `A` and `B` within percent tolerance `tol`
 pub fn double_comparable(a: f64, b: f64, tol: f64) -> bool {
     if !a.is_finite() || !b.is_finite()  { return false; }
@@ -7,6 +15,65 @@ pub fn double_comparable(a: f64, b: f64, tol: f64) -> bool {
     let b = b.abs();
     let largest = a.max(b);
     
-    if diff <= (largest * tol / 100.0)
-    { true } else { false }
+    diff <= (largest * tol / 100.0)
+}
+
+/// A directory of a test's own to put files in, which is removed when it's dropped
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    /// Make an empty directory, with a `name` that no other test uses
+    pub fn new(name: &str) -> TempDir {
+        let path = env::temp_dir().join(format!("sd3norm-test-{}-{}", process::id(), name));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        TempDir(path)
+    }
+
+    pub fn join<P: AsRef<Path>>(&self, path: P) -> PathBuf {
+        self.0.join(path)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// A normalized row with chip `id`, and a `value` in ng/day/10^6 cells if it has one
+pub fn mifc_row(id: &str, value: Option<f64>) -> MIFC {
+    MIFC {
+        id: id.to_string(), assay_plate_id: None, assay_well_id: None, method: "ELISA".to_string(),
+        target: "Albumin".to_string(), subtarget: None, sample_loc: "Efflux".to_string(),
+        day: 1.0, hour: 0.0, min: 0.0, value, value_unit: value.map(|_| SIUnit::ng_day_millioncells),
+        flag: None, exclude: None, notes: None, replicate: None, xref: None,
+    }
+}
+
+/// The cells of an SD3 row that normalizes, by column
+const GOOD_ROW: [(&str, &str); 15] = [
+    ("Chip ID", "A1"), ("Method/Kit", "ELISA"), ("Target/Analyte", "Albumin"), ("Sample Location", "Efflux"),
+    ("Day", "1"), ("Hour", "0"), ("Minute", "0"), ("Value", "10"), ("Value Unit", "ng/mL"),
+    ("Duration Sample Collection (days)", "1"), ("Duration Sample Collection (hours)", "0"),
+    ("Duration Sample Collection (minutes)", "0"), ("Sample Volume", "1"), ("Sample Volume Unit", "mL"),
+    ("Estimated Cell Number", "1000000"),
+];
+
+/// CSV text with a header of every SD3 column, then a row for each of `rows`. Each row is one that normalizes,
+/// with the cells of its (column, value) pairs changed
+pub fn sd3_csv(rows: &[&[(&str, &str)]]) -> String {
+    let columns: Vec<_> = MIFC_COLUMNS.iter().chain(NORMALIZATION_COLUMNS.iter()).map(|c| c.0).collect();
+    let mut text = columns.join(",") + "\n";
+    for changes in rows {
+        let cells: Vec<_> = columns.iter()
+            .map(|c| changes.iter().chain(GOOD_ROW.iter()).find(|(name, _)| name == c).map_or("", |(_, v)| *v))
+            .collect();
+        text += &(cells.join(",") + "\n");
+    }
+    text
 }
@@ -0,0 +1,174 @@
+use calamine::DataType;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use sd3::{SD3Error, InvalidPolicy, Column, MIFC_COLUMNS, NORMALIZATION_COLUMNS};
+use workbook::{self, InputFormat, Sheet, SheetRow};
+use report;
+
+/// A reason that part of a workbook would not be normalized
+#[derive(Debug, Serialize)]
+pub struct Problem {
+    pub workbook: PathBuf,
+    pub sheet: Option<String>,
+    pub cell: Option<String>,
+    pub column: Option<String>,
+    pub message: String,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: ", self.workbook.display())?;
+        match (&self.sheet, &self.cell) {
+            (Some(s), Some(c)) => write!(f, "{}!{}: ", s, c)?,
+            (Some(s), None) => write!(f, "{}: ", s)?,
+            _ => (),
+        }
+        write!(f, "{}", self.message)
+    }
+}
+
+/// Check every row of every sheet in a workbook, returning all found problems
+pub fn validate_workbook(wb_path: &Path, format: InputFormat, policy: InvalidPolicy) -> Vec<Problem> {
+    let problem = |sheet: Option<&Sheet>, message: String| Problem {
+        workbook: wb_path.to_path_buf(),
+        sheet: sheet.map(|s| s.name.clone()),
+        cell: None,
+        column: None,
+        message,
+    };
+
+    let sheets = match workbook::open_sheets(wb_path, format) {
+        Ok(s) => s,
+        Err(e) => return vec![problem(None, report::describe(&e))],
+    };
+
+    let mut problems = Vec::new();
+    for sheet in &sheets {
+        let headers = sheet.headers();
+        let missing: Vec<_> = MIFC_COLUMNS.iter()
+            .chain(NORMALIZATION_COLUMNS.iter())
+            .filter(|c| c.2 && !headers.iter().any(|h| h == c.0))
+            .map(|c| problem(Some(sheet), format!("missing column \"{}\"", c.0)))
+            .collect();
+        /* Every row would fail in the same way, so only report the missing columns */
+        if !missing.is_empty() {
+            problems.extend(missing);
+            continue;
+        }
+
+        let rows = match sheet.rows() {
+            Ok(r) => r,
+            Err(e) => {
+                problems.push(problem(Some(sheet), format!("could not parse sheet: {}", e)));
+                continue;
+            }
+        };
+        for row in rows {
+            problems.extend(diagnose_row(wb_path, sheet, &headers, row, policy));
+        }
+    }
+    problems
+}
+
+/// Find the reasons, if any, that a row would not be normalized
+fn diagnose_row(wb_path: &Path, sheet: &Sheet, headers: &[String], row: SheetRow, policy: InvalidPolicy)
+    -> Vec<Problem>
+{
+    let SheetRow { context, cells, record } = row;
+    let row = context.row;
+    let problem = |col: Option<usize>, message: String| Problem {
+        workbook: wb_path.to_path_buf(),
+        sheet: Some(sheet.name.clone()),
+        cell: Some(match col {
+            Some(c) => sheet.cell_ref(row, c),
+            None => format!("{0}:{0}", row),
+        }),
+        column: col.map(|c| headers[c].clone()),
+        message,
+    };
+    let position = |name: &str| headers.iter().position(|h| h == name);
+    let check_columns = |columns: &[Column]| -> Vec<Problem> {
+        columns.iter()
+            .filter_map(|c| position(c.0).map(|i| (c, i)))
+            .filter_map(|(c, i)| {
+                let cell = cells.get(i).unwrap_or(&DataType::Empty);
+                c.check(cell).map(|m| problem(Some(i), m))
+            })
+            .collect()
+    };
+
+    let (checked, message) = match record.and_then(|r| r.into_normalized(&context, policy)) {
+        Ok(_) | Err(SD3Error::Excluded(_)) => return Vec::new(),
+        /* Look at every cell, since there could be more than one with an unexpected value */
+        Err(e @ SD3Error::Deserialize { .. }) => (check_columns(&MIFC_COLUMNS), e.to_string()),
+        Err(e @ SD3Error::NoInfo(_)) => (check_columns(&NORMALIZATION_COLUMNS), e.to_string()),
+        Err(e) => return vec![problem(e.column().and_then(position), e.to_string())],
+    };
+
+    /* Fall back to reporting the whole row if no single cell is to blame */
+    if checked.is_empty() {
+        vec![problem(None, message)]
+    } else {
+        checked
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use utils::{TempDir, sd3_csv};
+
+    /// The problems with each row of an SD3 sheet that has the given `rows`
+    fn diagnose(rows: &[&[(&str, &str)]]) -> Vec<Problem> {
+        let sheet = workbook::read_delimited("study", sd3_csv(rows).as_bytes(), b',').unwrap();
+        let headers = sheet.headers();
+        sheet.rows().unwrap()
+            .flat_map(|row| diagnose_row(Path::new("study.csv"), &sheet, &headers, row, InvalidPolicy::Reject))
+            .collect()
+    }
+
+    #[test]
+    fn problems_are_blamed_on_cells() {
+        let problems = diagnose(&[
+            &[],
+            &[("Exclude", "x"), ("Value", "")],
+            &[("Day", "one"), ("Hour", "")],
+            &[("Sample Volume", "-1")],
+            &[("Value Unit", "ng/day")],
+            &[("Duration Sample Collection (days)", "0")],
+        ]);
+        let cells: Vec<_> = problems.iter()
+            .map(|p| (p.cell.as_deref().unwrap(), p.column.as_deref().unwrap_or("")))
+            .collect();
+
+        assert_eq!(cells, [("H4", "Day"), ("I4", "Hour"), ("U5", "Sample Volume"), ("L6", "Value Unit"), ("7:7", "")]);
+        assert_eq!(problems[2].message, "sample volume of -1 is not positive");
+    }
+
+    #[test]
+    fn problems_are_reported_by_workbook_sheet_and_cell() {
+        let dir = TempDir::new("validate");
+        let path = dir.join("study.csv");
+        fs::write(&path, sd3_csv(&[&[("Sample Volume", "0")]])).unwrap();
+        let problems = validate_workbook(&path, InputFormat::Auto, InvalidPolicy::Reject);
+        let json = ::serde_json::to_value(&problems[0]).unwrap();
+
+        assert_eq!(problems.len(), 1);
+        assert_eq!(problems[0].to_string(), format!("{}: study!U2: sample volume of 0 is not positive", path.display()));
+        assert_eq!(json["workbook"], path.display().to_string());
+        assert_eq!(json["sheet"], "study");
+        assert_eq!(json["cell"], "U2");
+        assert_eq!(json["column"], "Sample Volume");
+        assert_eq!(json["message"], "sample volume of 0 is not positive");
+        assert_eq!(json.as_object().unwrap().len(), 5);
+
+        fs::write(&path, "Chip ID,Day\nA1,1\n").unwrap();
+        let missing = validate_workbook(&path, InputFormat::Auto, InvalidPolicy::Reject);
+        assert!(missing.iter().all(|p| p.sheet.as_deref() == Some("study") && p.cell.is_none()));
+        assert_eq!(missing[0].to_string(), format!("{}: study: missing column \"Method/Kit\"", path.display()));
+
+        let unreadable = validate_workbook(&dir.join("gone.xlsx"), InputFormat::Auto, InvalidPolicy::Reject);
+        assert!(unreadable.len() == 1 && unreadable[0].sheet.is_none());
+    }
+}
@@ -0,0 +1,192 @@
+use failure::{Error, ResultExt};
+use notify::{EventKind, RecursiveMode, Watcher};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::time::{Duration, Instant, UNIX_EPOCH};
+use walkdir::WalkDir;
+
+/// How often pending files are checked to see if they have stopped changing
+const POLL: Duration = Duration::from_millis(250);
+
+/// The size and modification time of a file, to tell if it changed since it was last normalized
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+struct Stamp {
+    size: u64,
+    modified_secs: u64,
+    modified_nanos: u32,
+}
+
+impl Stamp {
+    fn of(path: &Path) -> Option<Stamp> {
+        let meta = fs::metadata(path).ok()?;
+        let modified = meta.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+        Some(Stamp { size: meta.len(), modified_secs: modified.as_secs(), modified_nanos: modified.subsec_nanos() })
+    }
+}
+
+/// What has been normalized so far, kept in a file so that a restarted watch doesn't redo it
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct State {
+    /// Each workbook that was normalized, as it was when it was normalized
+    inputs: BTreeMap<PathBuf, Stamp>,
+    /// The files written by normalizing, which are never read as inputs
+    outputs: BTreeSet<PathBuf>,
+}
+
+impl State {
+    fn load(path: &Path) -> Result<State, Error> {
+        if !path.exists() {
+            return Ok(State::default());
+        }
+        let file = File::open(path).context(format!("opening watch state <{}>", path.display()))?;
+        Ok(::serde_json::from_reader(file).context(format!("reading watch state <{}>", path.display()))?)
+    }
+
+    fn save(&self, path: &Path) -> Result<(), Error> {
+        /* Written next to the state, then renamed over it, so that a stopped watch never leaves half a file */
+        let partial = path.with_extension("partial");
+        ::serde_json::to_writer_pretty(File::create(&partial)?, self)
+            .context(format!("writing watch state <{}>", path.display()))?;
+        fs::rename(&partial, path)?;
+        Ok(())
+    }
+
+    fn is_unchanged(&self, path: &Path) -> bool {
+        self.inputs.get(path).is_some_and(|s| Stamp::of(path).as_ref() == Some(s))
+    }
+
+    /// Record what normalizing a batch of workbooks did, with each workbook's stamp from before it was normalized.
+    /// Failed workbooks aren't recorded, so they are retried when they change, or when the watch restarts
+    fn record<I>(&mut self, stamped: I, normalized: &Normalized)
+        where I: IntoIterator<Item = (PathBuf, Option<Stamp>)>
+    {
+        for (path, stamp) in stamped {
+            match stamp {
+                Some(stamp) if !normalized.failed.contains(&path) => { self.inputs.insert(path, stamp); },
+                _ => { self.inputs.remove(&path); },
+            }
+        }
+        self.outputs.extend(normalized.outputs.iter().filter_map(|o| o.canonicalize().ok()));
+    }
+}
+
+/// What normalizing a batch of workbooks did
+pub struct Normalized {
+    /// The files that were written
+    pub outputs: Vec<PathBuf>,
+    /// The workbooks that could not be normalized
+    pub failed: Vec<PathBuf>,
+}
+
+/// Watch `dir` for new or changed workbooks, and pass each batch of them to `normalize` once they have stopped
+/// changing for `debounce`. `is_input` tells which files are workbooks.
+/// What was normalized is kept in the `state` file, so that only files that change are normalized again.
+/// This only returns if the watch stops working
+pub fn watch<F, N>(dir: &Path, state_path: &Path, debounce: Duration, is_input: F, mut normalize: N)
+    -> Result<(), Error>
+    where F: Fn(&Path) -> bool,
+          N: FnMut(&[PathBuf]) -> Normalized
+{
+    let dir = dir.canonicalize().context(format!("finding watched directory <{}>", dir.display()))?;
+    let mut state = State::load(state_path)?;
+
+    let (tx, rx) = mpsc::channel();
+    let mut watcher = ::notify::recommended_watcher(tx)?;
+    watcher.watch(&dir, RecursiveMode::Recursive)
+        .context(format!("watching <{}>", dir.display()))?;
+    info!("Watching <{}> for workbooks", dir.display());
+
+    /* Files dropped while nothing was watching are checked too, once the watch has started so none are missed */
+    let mut pending: BTreeMap<PathBuf, Instant> = WalkDir::new(&dir)
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_type().is_file())
+        .map(|e| (e.path().to_path_buf(), Instant::now()))
+        .collect();
+
+    loop {
+        match rx.recv_timeout(POLL) {
+            Ok(Ok(event)) => match event.kind {
+                EventKind::Access(_) | EventKind::Remove(_) => (),
+                _ => for path in event.paths {
+                    pending.insert(path, Instant::now());
+                },
+            },
+            Ok(Err(e)) => warn!("problem watching <{}>: {}", dir.display(), e),
+            Err(RecvTimeoutError::Timeout) => (),
+            Err(RecvTimeoutError::Disconnected) => bail!("stopped watching <{}>", dir.display()),
+        }
+
+        let now = Instant::now();
+        let settled: Vec<_> = pending.iter()
+            .filter(|&(_, changed)| now.duration_since(*changed) >= debounce)
+            .map(|(path, _)| path.clone())
+            .collect();
+        if settled.is_empty() {
+            continue;
+        }
+        for path in &settled {
+            pending.remove(path);
+        }
+
+        let changed: Vec<_> = settled.into_iter()
+            .filter(|p| p.is_file() && !state.outputs.contains(p) && !state.is_unchanged(p) && is_input(p))
+            .collect();
+        if changed.is_empty() {
+            continue;
+        }
+
+        /* Stamped before normalizing, so that a workbook saved again while it's being normalized is redone */
+        let stamps: Vec<_> = changed.iter().map(|p| Stamp::of(p)).collect();
+        info!("Normalizing {} new or changed workbook(s)", changed.len());
+        let normalized = normalize(&changed);
+        state.record(changed.into_iter().zip(stamps), &normalized);
+        state.save(state_path)?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use utils::TempDir;
+
+    #[test]
+    fn state_remembers_unchanged_files() {
+        let dir = TempDir::new("watch");
+        let input = dir.join("watched.xlsx");
+        let state_path = dir.join("watch.json");
+        fs::write(&input, "first").unwrap();
+
+        let mut state = State::default();
+        assert!(!state.is_unchanged(&input));
+        state.inputs.insert(input.clone(), Stamp::of(&input).unwrap());
+        state.save(&state_path).unwrap();
+        let state = State::load(&state_path).unwrap();
+        let unchanged = state.is_unchanged(&input);
+        fs::write(&input, "second").unwrap();
+        let changed = !state.is_unchanged(&input);
+
+        assert!(unchanged);
+        assert!(changed);
+    }
+
+    #[test]
+    fn only_workbooks_that_were_normalized_as_stamped_are_unchanged() {
+        let dir = TempDir::new("watch-record");
+        let (saved, resaved, failed) = (dir.join("saved.xlsx"), dir.join("resaved.xlsx"), dir.join("failed.xlsx"));
+        for path in &[&saved, &resaved, &failed] {
+            fs::write(path, "first").unwrap();
+        }
+        let stamped: Vec<_> = [&saved, &resaved, &failed].iter().map(|p| (p.to_path_buf(), Stamp::of(p))).collect();
+        /* Saved again while it was being normalized */
+        fs::write(&resaved, "second").unwrap();
+
+        let mut state = State::default();
+        state.record(stamped, &Normalized { outputs: Vec::new(), failed: vec![failed.clone()] });
+        assert!(state.is_unchanged(&saved));
+        assert!(!state.is_unchanged(&resaved));
+        assert!(!state.is_unchanged(&failed));
+    }
+}
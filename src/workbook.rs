@@ -0,0 +1,381 @@
+use calamine::{Reader, Range, Cell, DataType, DeError, RangeDeserializerBuilder, open_workbook_auto};
+use calamine::{Xlsx, Xlsb, Xls, Ods};
+use failure::{Error, Fail, ResultExt};
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Cursor, Read};
+use std::path::Path;
+use std::str::FromStr;
+use sd3::{SD3, SD3Error, RowContext, Expect, MIFC_COLUMNS, NORMALIZATION_COLUMNS};
+
+/// The INPUT that reads a workbook or CSV file from stdin
+pub const STDIN: &str = "-";
+
+/// How input files are read
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum InputFormat {
+    /// Tell the format of each input from its extension
+    Auto,
+    /// A spreadsheet workbook: excel (.xlsx, .xlsm, .xls, .xlsb) or OpenDocument (.ods)
+    Excel,
+    /// Comma-separated text, read as a workbook with a single sheet
+    Csv,
+    /// Tab-separated text, read as a workbook with a single sheet
+    Tsv,
+}
+
+/// Every file extension that can be read
+pub const EXTENSIONS: [&str; 8] = ["xlsx", "xlsm", "xls", "xlsb", "ods", "csv", "tsv", "tab"];
+
+impl InputFormat {
+    /// The format of files with an extension, if it's one that can be read
+    pub fn from_ext(ext: &str) -> Option<InputFormat> {
+        match ext.to_lowercase().as_str() {
+            "xlsx" | "xlsm" | "xls" | "xlsb" | "ods" => Some(InputFormat::Excel),
+            "csv" => Some(InputFormat::Csv),
+            "tsv" | "tab" => Some(InputFormat::Tsv),
+            _ => None,
+        }
+    }
+
+    /// The format of a file, if its extension is one that can be read
+    pub fn from_extension(path: &Path) -> Option<InputFormat> {
+        InputFormat::from_ext(&path.extension()?.to_string_lossy())
+    }
+
+    /// The format that a file is read in, when this is the `--input-format`
+    pub fn of(self, path: &Path) -> InputFormat {
+        match self {
+            InputFormat::Auto => InputFormat::from_extension(path).unwrap_or(InputFormat::Excel),
+            f => f,
+        }
+    }
+
+    /// Check if a file found in an input directory should be read in this format.
+    /// If `allowed` isn't empty, the file's extension must also be one of those
+    pub fn accepts(self, path: &Path, allowed: &[String]) -> bool {
+        let allowed = allowed.is_empty() || path.extension()
+            .is_some_and(|e| allowed.iter().any(|a| e.to_string_lossy().eq_ignore_ascii_case(a)));
+        match InputFormat::from_extension(path) {
+            Some(format) => allowed && (self == InputFormat::Auto || self == format),
+            None => false,
+        }
+    }
+}
+
+impl FromStr for InputFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(InputFormat::Auto),
+            "excel" => Ok(InputFormat::Excel),
+            "csv" => Ok(InputFormat::Csv),
+            "tsv" => Ok(InputFormat::Tsv),
+            _ => Err(format_err!("unknown input format <{}>", s)),
+        }
+    }
+}
+
+impl fmt::Display for InputFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            InputFormat::Auto => "auto",
+            InputFormat::Excel => "excel",
+            InputFormat::Csv => "csv",
+            InputFormat::Tsv => "tsv",
+        })
+    }
+}
+
+/// One worksheet of an input workbook, read fully into memory
+pub struct Sheet {
+    pub name: String,
+    pub range: Range<DataType>,
+}
+
+/// A single data row of a worksheet along with its deserialized SD3 record
+pub struct SheetRow<'a> {
+    pub context: RowContext,
+    pub cells: &'a [DataType],
+    pub record: Result<SD3, SD3Error>,
+}
+
+/// Open a workbook, or a CSV or TSV file, and read all of its worksheets
+pub fn open_sheets(wb_path: &Path, format: InputFormat) -> Result<Vec<Sheet>, Error> {
+    if wb_path.as_os_str() == STDIN {
+        return read_stdin(io::stdin().lock(), format);
+    }
+    let delimiter = match format.of(wb_path) {
+        InputFormat::Csv => b',',
+        InputFormat::Tsv => b'\t',
+        _ => {
+            let workbook = open_workbook_auto(wb_path)
+                .context(format!("opening workbook <{}>", wb_path.display()))?;
+            return read_sheets(workbook);
+        },
+    };
+
+    /* Text files have a single sheet, which is named after the file */
+    let name = wb_path.file_stem().map_or("Sheet1".into(), |s| s.to_string_lossy());
+    let data = fs::read(wb_path)
+        .context(format!("opening input <{}>", wb_path.display()))?;
+    let sheet = read_delimited(&name, &data, delimiter)
+        .context(format!("reading input <{}>", wb_path.display()))?;
+    Ok(vec![sheet])
+}
+
+/// Read all of the worksheets of an opened workbook
+fn read_sheets<R: Reader>(mut workbook: R) -> Result<Vec<Sheet>, Error>
+    where R::Error: Fail
+{
+    let names = workbook.sheet_names().to_vec();
+
+    names.into_iter()
+        .map(|name| {
+            let range = workbook.worksheet_range(&name)
+                .ok_or_else(|| format_err!("workbook does not contain sheet <{}>", name))?
+                .context(format!("reading sheet <{}>", name))?;
+            Ok(Sheet { name, range })
+        })
+        .collect()
+}
+
+/// Check if a CSV or TSV file has any of the SD3 normalization columns in its first line.
+/// Normalized outputs don't, so this keeps them from being read as inputs when they're in an input directory
+pub fn is_sd3_text(path: &Path, format: InputFormat) -> bool {
+    let delimiter = match format.of(path) {
+        InputFormat::Csv => b',',
+        InputFormat::Tsv => b'\t',
+        _ => return true,
+    };
+    let headers = File::open(path).ok().and_then(|f| {
+        csv::ReaderBuilder::new().delimiter(delimiter).from_reader(f).headers().ok().cloned()
+    });
+    /* Let files that can't be read fail later, with an error that says why */
+    headers.is_none_or(|h| h.iter().any(|h| NORMALIZATION_COLUMNS.iter().any(|c| c.0 == h.trim())))
+}
+
+/// Read a whole workbook or CSV file from stdin (or another `input`), telling which it is from its first bytes
+/// unless its `format` is given
+fn read_stdin<R: Read>(mut input: R, format: InputFormat) -> Result<Vec<Sheet>, Error> {
+    let mut data = Vec::new();
+    input.read_to_end(&mut data).context("reading from stdin")?;
+
+    let is_zip = data.starts_with(b"PK\x03\x04");
+    let is_ole = data.starts_with(&[0xD0, 0xCF, 0x11, 0xE0]);
+    let sheets = if format == InputFormat::Csv || format == InputFormat::Tsv || (!is_zip && !is_ole) {
+        /* Without a format, guess that text with tabs but no commas in its first line is TSV */
+        let first_line = data.split(|&b| b == b'\n').next().unwrap_or(&[]);
+        let tsv = format == InputFormat::Tsv ||
+            (format == InputFormat::Auto && first_line.contains(&b'\t') && !first_line.contains(&b','));
+        read_delimited("stdin", &data, if tsv { b'\t' } else { b',' }).map(|s| vec![s])
+    } else if is_zip {
+        /* xlsx, xlsb and ods workbooks are all zip archives */
+        Xlsx::new(Cursor::new(&data)).map_err(Error::from).and_then(read_sheets)
+            .or_else(|_| Xlsb::new(Cursor::new(&data)).map_err(Error::from).and_then(read_sheets))
+            .or_else(|_| Ods::new(Cursor::new(&data)).map_err(Error::from).and_then(read_sheets))
+    } else {
+        Xls::new(Cursor::new(&data)).map_err(Error::from).and_then(read_sheets)
+    };
+    Ok(sheets.context("reading workbook from stdin")?)
+}
+
+/// Read CSV (or other `delimiter`-separated) text into a single sheet named `name`
+pub fn read_delimited(name: &str, data: &[u8], delimiter: u8) -> Result<Sheet, Error> {
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .delimiter(delimiter)
+        .flexible(true)
+        .from_reader(data);
+
+    /* Text has no cell types, so only read numbers in the SD3 columns that hold them.
+     * Otherwise, an ID like "1001" would be a number instead of text */
+    let mut numeric = Vec::new();
+    let mut cells = Vec::new();
+    for (i, record) in reader.records().enumerate() {
+        let record = record?;
+        let row = record.position().map_or(i as u32, |p| line_of(data, p) - 1);
+        if i == 0 {
+            numeric = record.iter()
+                .map(|h| MIFC_COLUMNS.iter().chain(NORMALIZATION_COLUMNS.iter())
+                    .any(|c| c.0 == h.trim() && c.1 == Expect::Number))
+                .collect();
+        }
+        for (col, field) in record.iter().enumerate() {
+            let field = field.trim();
+            let value = match field.parse::<f64>() {
+                _ if field.is_empty() => continue,
+                Ok(n) if n.is_finite() && numeric.get(col) == Some(&true) => DataType::Float(n),
+                _ => DataType::String(field.to_string()),
+            };
+            cells.push(Cell::new((row, col as u32), value));
+        }
+    }
+    Ok(Sheet { name: name.to_string(), range: Range::from_sparse(cells) })
+}
+
+/// The line that a record starts on. Its position is where reading it began, which is before any blank lines
+/// that were skipped, so those are counted too to keep rows matching the lines of the file
+fn line_of(data: &[u8], position: &csv::Position) -> u32 {
+    let skipped = data[position.byte() as usize..].iter()
+        .take_while(|&&b| b == b'\r' || b == b'\n')
+        .filter(|&&b| b == b'\n')
+        .count();
+    (position.line() as usize + skipped) as u32
+}
+
+impl Sheet {
+    /// The values in the first row of the sheet
+    pub fn headers(&self) -> Vec<String> {
+        self.range.rows()
+            .next()
+            .map(|r| r.iter().map(|c| c.to_string()).collect())
+            .unwrap_or_default()
+    }
+
+    /// Deserialize each non-blank row after the header into an SD3 record
+    pub fn rows<'a>(&'a self) -> Result<impl Iterator<Item = SheetRow<'a>> + 'a, DeError> {
+        let records = RangeDeserializerBuilder::new()
+            .has_headers(true)
+            .from_range::<_, SD3>(&self.range)?;
+        let headers = self.headers();
+        /* The header row is at the start of the range; data follows directly after */
+        let first_row = self.range.start().map_or(0, |(r, _)| r) + 2;
+
+        Ok(self.range.rows()
+            .skip(1)
+            .zip(records)
+            .enumerate()
+            .filter(|(_, (cells, _))| !cells.iter().all(|c| *c == DataType::Empty))
+            .map(move |(i, (cells, record))| {
+                let context = RowContext { sheet: self.name.clone(), row: first_row + i as u32 };
+                let record = record.map_err(|e| deserialize_error(&context, &headers, cells, e));
+                SheetRow { context, cells, record }
+            })
+        )
+    }
+
+    /// Excel-style reference (e.g. "M14") to the cell in column `col` (0-indexed, relative to
+    /// the start of the sheet's data) of worksheet row number `row`
+    pub fn cell_ref(&self, row: u32, col: usize) -> String {
+        let first_col = self.range.start().map_or(0, |(_, c)| c);
+        format!("{}{}", column_letters(first_col + col as u32), row)
+    }
+}
+
+/// Find the first SD3 column in a row with an unexpected value to blame for a deserialization error
+fn deserialize_error(ctx: &RowContext, headers: &[String], cells: &[DataType], e: DeError) -> SD3Error {
+    let blamed = MIFC_COLUMNS.iter()
+        .filter_map(|c| headers.iter().position(|h| h == c.0).map(|i| (c, i)))
+        .find_map(|(c, i)| c.check(cells.get(i).unwrap_or(&DataType::Empty)).map(|m| (c.0, m)));
+
+    match blamed {
+        Some((column, message)) => SD3Error::Deserialize {
+            ctx: ctx.clone(),
+            column: Some(column),
+            message: format!("column \"{}\" {}", column, message),
+        },
+        None => SD3Error::Deserialize { ctx: ctx.clone(), column: None, message: e.to_string() },
+    }
+}
+
+/// Convert a 0-indexed column number into Excel column letters
+fn column_letters(col: u32) -> String {
+    let mut letters = Vec::new();
+    let mut n = col + 1;
+    while n > 0 {
+        let rem = (n - 1) % 26;
+        letters.push((b'A' + rem as u8) as char);
+        n = (n - 1) / 26;
+    }
+    letters.iter().rev().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn excel_column_letters() {
+        assert_eq!(column_letters(0), "A");
+        assert_eq!(column_letters(12), "M");
+        assert_eq!(column_letters(25), "Z");
+        assert_eq!(column_letters(26), "AA");
+        assert_eq!(column_letters(51), "AZ");
+        assert_eq!(column_letters(702), "AAA");
+    }
+
+    #[test]
+    fn files_are_accepted_by_extension() {
+        let ods = vec!["ods".to_string()];
+        assert!(InputFormat::Auto.accepts(Path::new("a.XLSB"), &[]));
+        assert!(InputFormat::Auto.accepts(Path::new("a.ods"), &ods));
+        assert!(!InputFormat::Auto.accepts(Path::new("a.xlsx"), &ods));
+        assert!(!InputFormat::Auto.accepts(Path::new("a.txt"), &[]));
+        assert!(InputFormat::Tsv.accepts(Path::new("a.tab"), &[]));
+        assert!(!InputFormat::Csv.accepts(Path::new("a.tsv"), &[]));
+        assert!(!InputFormat::Excel.accepts(Path::new("a.ods"), &["xlsx".to_string()]));
+    }
+
+    #[test]
+    fn delimited_cell_types() {
+        let text = "Chip ID\tDay\tValue\tNotes\n1001\t2\t\t3\n";
+        let sheet = read_delimited("data", text.as_bytes(), b'\t').unwrap();
+        let rows: Vec<_> = sheet.range.rows().collect();
+
+        assert_eq!(sheet.headers(), ["Chip ID", "Day", "Value", "Notes"]);
+        assert_eq!(rows[1], [
+            DataType::String("1001".to_string()),
+            DataType::Float(2.0),
+            DataType::Empty,
+            DataType::String("3".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn stdin_is_read_as_the_format_its_bytes_start_with() {
+        let read = |data: &[u8], format| read_stdin(data, format);
+        let first_row = |sheets: &[Sheet]| -> Vec<_> { sheets[0].range.rows().next().unwrap().to_vec() };
+        let text = |cells: &[&str]| -> Vec<_> { cells.iter().map(|c| DataType::String(c.to_string())).collect() };
+
+        let csv = read(b"Chip ID,Notes\tx\n", InputFormat::Auto).unwrap();
+        assert_eq!(csv[0].name, "stdin");
+        assert_eq!(first_row(&csv), text(&["Chip ID", "Notes\tx"]));
+        let tsv = read(b"Chip ID\tNotes\n", InputFormat::Auto).unwrap();
+        assert_eq!(first_row(&tsv), text(&["Chip ID", "Notes"]));
+        let csv = read(b"Chip ID\tNotes\n", InputFormat::Csv).unwrap();
+        assert_eq!(first_row(&csv), text(&["Chip ID\tNotes"]));
+
+        let mut workbook = rust_xlsxwriter::Workbook::new();
+        workbook.add_worksheet().set_name("Day 1").unwrap().write_string(0, 0, "Chip ID").unwrap();
+        workbook.add_worksheet().set_name("Day 2").unwrap().write_number(0, 0, 2.0).unwrap();
+        let xlsx = read(&workbook.save_to_buffer().unwrap(), InputFormat::Auto).unwrap();
+        let names: Vec<_> = xlsx.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(names, ["Day 1", "Day 2"]);
+        assert_eq!(first_row(&xlsx), text(&["Chip ID"]));
+        assert_eq!(first_row(&xlsx[1..]), [DataType::Float(2.0)]);
+
+        /* Archives and OLE files that aren't workbooks are errors, instead of being read as text */
+        assert!(read(b"PK\x03\x04Chip ID,Day\n", InputFormat::Auto).is_err());
+        let ole = read(b"\xD0\xCF\x11\xE0Chip ID,Day\n", InputFormat::Auto).err().unwrap();
+        assert!(ole.iter_chain().any(|c| c.downcast_ref::<calamine::XlsError>().is_some()));
+    }
+
+    #[test]
+    fn delimited_rows_are_numbered_by_line() {
+        let text = "Chip ID,Day\n1001,1\n\n1002,x\n";
+        let sheet = read_delimited("data", text.as_bytes(), b',').unwrap();
+        let rows: Vec<_> = sheet.rows().unwrap().collect();
+
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0].context.row, 2);
+        assert_eq!(rows[1].context.row, 4);
+        assert_eq!(sheet.cell_ref(rows[1].context.row, 1), "B4");
+
+        let text = "\r\nChip ID,Day\r\n1001,1\r\n\r\n\r\n1002,x\r\n";
+        let sheet = read_delimited("data", text.as_bytes(), b',').unwrap();
+        let rows: Vec<_> = sheet.rows().unwrap().map(|r| r.context.row).collect();
+        assert_eq!(rows, [3, 6]);
+    }
+}
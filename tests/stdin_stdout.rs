@@ -0,0 +1,49 @@
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+/// An SD3 sheet of two rows, the second with a zero sample volume
+const SD3: &str = "\
+Chip ID,Method/Kit,Target/Analyte,Sample Location,Day,Hour,Minute,Value,Value Unit,Replicate,\
+Duration Sample Collection (days),Duration Sample Collection (hours),Duration Sample Collection (minutes),\
+Sample Volume,Sample Volume Unit,Estimated Cell Number
+A1,ELISA,Albumin,Efflux,1,0,0,10,ng/mL,0.1,1,0,0,1,mL,1000000
+A2,ELISA,Albumin,Efflux,1,0,0,10,ng/mL,,1,0,0,0,mL,1000000
+";
+
+/// Run sd3norm with `args`, writing `stdin` to it, and return its exit code and stdout
+fn sd3norm(args: &[&str], stdin: &str) -> (Option<i32>, String) {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_sd3norm"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    /* A run that fails before reading its stdin closes it */
+    let _ = child.stdin.take().unwrap().write_all(stdin.as_bytes());
+    let output = child.wait_with_output().unwrap();
+    (output.status.code(), String::from_utf8(output.stdout).unwrap())
+}
+
+#[test]
+fn stdin_is_normalized_to_stdout() {
+    let (code, out) = sd3norm(&["--stdout", "-"], SD3);
+    let lines: Vec<_> = out.lines().collect();
+
+    assert_eq!(code, Some(3));
+    assert_eq!(lines.len(), 2);
+    assert!(lines[0].starts_with("Chip ID,") && lines[0].ends_with(",Source File,Source Sheet"));
+    assert!(lines[1].starts_with("A1,") && lines[1].ends_with(",0.1,,-,stdin"));
+
+    let (code, out) = sd3norm(&["--stdout", "-f", "ndjson", "-"], SD3);
+    assert_eq!(code, Some(3));
+    assert_eq!(out.lines().count(), 1);
+    assert!(out.contains("\"Chip ID\":\"A1\""));
+}
+
+#[test]
+fn stdin_needs_an_output_without_a_file_name() {
+    let (code, out) = sd3norm(&["-"], SD3);
+    assert_eq!(code, Some(2));
+    assert_eq!(out, "");
+}